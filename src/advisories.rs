@@ -0,0 +1,292 @@
+use git2::{Repository, BranchType, Error, ResetType};
+use super::config;
+use git_utils;
+use proxy;
+use glob::glob;
+use semver::{Version, VersionReq};
+use supervisor::Supervisor;
+use std::fs::File;
+use std::io::prelude::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use toml;
+
+const TASK : &str = "advisories";
+
+#[derive(Deserialize)]
+struct AdvisoryFile {
+    advisory: AdvisoryMetadata,
+    #[serde(default)]
+    versions: AdvisoryVersions,
+}
+
+#[derive(Deserialize)]
+struct AdvisoryMetadata {
+    id: String,
+    package: String,
+    informational: Option<String>,
+    withdrawn: Option<toml::Value>,
+    // Versions of the V1 format of the advisory database, before the `[versions]` table
+    #[serde(default)]
+    patched_versions: Vec<String>,
+    #[serde(default)]
+    unaffected_versions: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct AdvisoryVersions {
+    #[serde(default)]
+    patched: Vec<String>,
+    #[serde(default)]
+    unaffected: Vec<String>,
+}
+
+/// Vulnerability of a crate, affecting all versions which are neither patched nor unaffected
+#[derive(Debug, Clone)]
+pub struct Advisory {
+    pub id: String,
+    pub package: String,
+    patched: Vec<VersionReq>,
+    unaffected: Vec<VersionReq>,
+}
+
+impl Advisory {
+    pub fn affects(&self, name: &str, version: &str) -> bool {
+        if self.package != name {
+            return false;
+        }
+        match Version::parse(version) {
+            Ok(ref version) => !self.patched.iter().chain(self.unaffected.iter()).any(|requirement| requirement.matches(version)),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Parses an advisory, either the TOML front matter of a markdown file or a plain TOML file.
+/// Withdrawn & informational advisories, e.g. unmaintained crates, are left out.
+/// Invalid patched or unaffected requirements are skipped & added to `errors`, so the advisory affects more versions
+/// rather than none.
+fn parse_advisory(contents: &str, markdown: bool, errors: &mut Vec<String>) -> Result<Option<Advisory>, String> {
+    let toml_str = if markdown {
+        contents.trim_start()
+            .strip_prefix("```toml")
+            .and_then(|front_matter| front_matter.split("\n```").next())
+            .ok_or_else(|| String::from("No TOML front matter"))?
+    } else {
+        contents
+    };
+    let file = toml::from_str::<AdvisoryFile>(toml_str).map_err(|e| e.to_string())?;
+    if file.advisory.withdrawn.is_some() || file.advisory.informational.is_some() {
+        return Ok(None);
+    }
+    let mut parse_requirements = |requirements: Vec<String>| requirements.iter()
+        .filter_map(|requirement| VersionReq::parse(requirement)
+            .map_err(|e| errors.push(format!("Skipping invalid version requirement {:?}: {:?}", requirement, e)))
+            .ok())
+        .collect::<Vec<_>>();
+    let patched = parse_requirements([file.versions.patched, file.advisory.patched_versions].concat());
+    let unaffected = parse_requirements([file.versions.unaffected, file.advisory.unaffected_versions].concat());
+    Ok(Some(Advisory { id: file.advisory.id, package: file.advisory.package, patched, unaffected }))
+}
+
+/// Reads the advisories under `crates/<name>/` of the advisory database,
+/// with the errors of the advisories which couldn't be read or only partly
+pub fn read_advisories(folder: &str) -> (Vec<Advisory>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut advisories = ["md", "toml"].iter()
+        .flat_map(|extension| glob(&format!("{}/crates/*/*.{}", folder, extension)).expect("Could not match advisory glob pattern"))
+        .filter_map(Result::ok)
+        .filter_map(|path| {
+            let mut contents = String::new();
+            File::open(&path).and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|e| errors.push(format!("Could not read advisory {:?}: {:?}", path, e)))
+                .ok()?;
+            let markdown = path.extension().map(|extension| extension == "md").unwrap_or(false);
+            let mut skipped = Vec::new();
+            let advisory = parse_advisory(&contents, markdown, &mut skipped)
+                .map_err(|e| errors.push(format!("Ignoring advisory {:?}: {}", path, e)));
+            errors.extend(skipped.into_iter().map(|e| format!("{} in advisory {:?}", e, path)));
+            advisory.ok()?
+        })
+        .collect::<Vec<_>>();
+    advisories.sort_by(|a, b| a.id.cmp(&b.id));
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    (advisories, errors)
+}
+
+/// Clones the advisory database if needed, then resets it to the tip of the upstream branch
+fn update_advisory_repo(config: &config::Advisories, http: &config::UpstreamHttp) -> Result<(), Error> {
+    let upstream_proxy = proxy::proxy_for(http, config.upstream());
+    let repo = if Path::new(&config.folder).exists() {
+        let repo = Repository::open(&config.folder)?;
+        git_utils::set_origin_proxy(&repo, upstream_proxy.as_deref())?;
+        repo
+    } else {
+        println!("Cloning advisory database {}", config.upstream());
+        git_utils::clone_repo(config.upstream(), &config.folder, &config::GitTransport::default(), upstream_proxy.as_deref())?
+    };
+    git_utils::set_stall_timeout(&repo, http.stall_timeout())?;
+    git_utils::fetch_origin_branch(&repo, config.upstream(), config.branch(), &config::GitTransport::default())?;
+    let remote_branch = repo.find_branch(&format!("origin/{}", config.branch()), BranchType::Remote)?;
+    let tip = remote_branch.get().peel_to_commit()?;
+    repo.reset(tip.as_object(), ResetType::Hard, None)
+}
+
+/// Advisories of the local advisory database, reloaded on every update
+pub struct AdvisoryDb {
+    pub policy: config::AdvisoryPolicy,
+    registries: Vec<String>,
+    advisories: RwLock<Vec<Advisory>>,
+}
+
+impl AdvisoryDb {
+    pub fn applies_to(&self, registry_name: &str) -> bool {
+        self.registries.is_empty() || self.registries.iter().any(|name| name == registry_name)
+    }
+
+    pub fn advisories(&self) -> Vec<Advisory> {
+        self.advisories.read().map(|advisories| advisories.clone()).unwrap_or_default()
+    }
+
+    pub fn is_affected(&self, name: &str, version: &str) -> bool {
+        self.advisories.read()
+            .map(|advisories| advisories.iter().any(|advisory| advisory.affects(name, version)))
+            .unwrap_or(false)
+    }
+
+    /// Reloads the advisories, returning the errors of the ones which couldn't be read entirely
+    fn reload(&self, folder: &str) -> Vec<String> {
+        let (advisories, errors) = read_advisories(folder);
+        println!("Loaded {} advisories", advisories.len());
+        if let Ok(mut current) = self.advisories.write() {
+            *current = advisories;
+        }
+        errors
+    }
+}
+
+/// Loads the advisories of the local advisory database as they are
+pub fn load(config: &config::Advisories) -> Arc<AdvisoryDb> {
+    let db = Arc::new(AdvisoryDb {
+        policy: config.policy,
+        registries: config.registries.clone(),
+        advisories: RwLock::new(Vec::new()),
+    });
+    db.reload(&config.folder);
+    db
+}
+
+/// Loads the advisory database, then keeps it up to date in a supervised task until shutting down.
+/// While upstream is unavailable, the update is retried with a backoff.
+/// Advisories which couldn't be read entirely are reported as the last error of the task.
+pub fn start(config: &config::Advisories, http: &config::UpstreamHttp, supervisor: &Arc<Supervisor>) -> Arc<AdvisoryDb> {
+    let db = load(config);
+    let config = config.clone();
+    let http = http.clone();
+    let updated_db = db.clone();
+    let task_supervisor = supervisor.clone();
+    supervisor.supervise(TASK, move || {
+        let update_interval = Duration::from_secs(u64::from(config.update_interval));
+        loop {
+            let report_errors = |errors: Vec<String>| if !errors.is_empty() {
+                task_supervisor.report_error(TASK, format!("Could not read all advisories: {}", errors.join("; ")));
+            };
+            let wait = if config.local {
+                report_errors(updated_db.reload(&config.folder));
+                update_interval
+            } else {
+                match update_advisory_repo(&config, &http) {
+                    Ok(()) => {
+                        task_supervisor.report_success(TASK);
+                        report_errors(updated_db.reload(&config.folder));
+                        update_interval
+                    },
+                    Err(e) => {
+                        eprintln!("Could not update advisory database: {:?}", e);
+                        let failures = task_supervisor.report_failure(TASK, e.message().to_string());
+                        task_supervisor.backoff(failures).min(update_interval)
+                    },
+                }
+            };
+            if !task_supervisor.sleep(wait) {
+                break;
+            }
+        }
+    });
+    db
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use git2::{IndexAddOption, Signature};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn read_affected_versions() {
+        let (advisories, errors) = read_advisories("test/advisories");
+        assert!(errors.is_empty());
+        let ids = advisories.iter().map(|advisory| advisory.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["RUSTSEC-2019-0011", "RUSTSEC-2021-0003"], ids);
+        let smallvec = &advisories[1];
+        assert!(smallvec.affects("smallvec", "1.6.0"));
+        assert!(smallvec.affects("smallvec", "0.6.3"));
+        assert!(!smallvec.affects("smallvec", "1.6.1"));
+        assert!(!smallvec.affects("smallvec", "0.6.2"));
+        assert!(!smallvec.affects("smallvec-ext", "1.0.0"));
+        assert!(advisories[0].affects("memoffset", "0.4.9"));
+        assert!(!advisories[0].affects("memoffset", "0.5.0"));
+    }
+
+    #[test]
+    fn skip_invalid_requirements() {
+        let contents = "[advisory]\nid = \"RUSTSEC-0000-0000\"\npackage = \"smallvec\"\n\n\
+            [versions]\npatched = [\">= 1.6.1\", \"^0.6.14 not a requirement\"]\nunaffected = [\"< 0.6.3\"]\n";
+        let mut errors = Vec::new();
+        let advisory = parse_advisory(contents, false, &mut errors).unwrap().unwrap();
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("^0.6.14 not a requirement"));
+        // The versions only patched by the invalid requirement are affected
+        assert!(advisory.affects("smallvec", "0.6.14"));
+        assert!(!advisory.affects("smallvec", "1.6.1"));
+        assert!(!advisory.affects("smallvec", "0.6.2"));
+    }
+
+    #[test]
+    fn update_from_upstream_repository() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-advisories-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let upstream_dir = dir.join("upstream");
+        let advisory_dir = upstream_dir.join("crates").join("smallvec");
+        fs::create_dir_all(&advisory_dir).unwrap();
+        fs::copy("test/advisories/crates/smallvec/RUSTSEC-2021-0003.md", advisory_dir.join("RUSTSEC-2021-0003.md")).unwrap();
+        let upstream = Repository::init(&upstream_dir).unwrap();
+        let mut index = upstream.index().unwrap();
+        index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None).unwrap();
+        let tree = upstream.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("rustsec", "rustsec@rust-lang.org").unwrap();
+        upstream.commit(Some("HEAD"), &signature, &signature, "Add advisory", &tree, &[]).unwrap();
+
+        let config = config::Advisories {
+            folder: String::from(dir.join("advisory-db").to_str().unwrap()),
+            upstream: Some(String::from(upstream_dir.to_str().unwrap())),
+            branch: Some(String::from("master")),
+            local: false,
+            update_interval: 3600,
+            policy: config::AdvisoryPolicy::Flag,
+            registries: Vec::new(),
+        };
+        update_advisory_repo(&config, &config::UpstreamHttp::default()).unwrap();
+        update_advisory_repo(&config, &config::UpstreamHttp::default()).unwrap();
+        let (advisories, _) = read_advisories(&config.folder);
+        assert_eq!(1, advisories.len());
+        assert!(advisories[0].affects("smallvec", "1.0.0"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
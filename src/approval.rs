@@ -0,0 +1,204 @@
+use reloadable::{self, ReloadableFile};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Approval {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl Approval {
+    fn parse(state: &str) -> Option<Approval> {
+        match state {
+            "pending" => Some(Approval::Pending),
+            "approved" => Some(Approval::Approved),
+            "rejected" => Some(Approval::Rejected),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Approval::Pending => "pending",
+            Approval::Approved => "approved",
+            Approval::Rejected => "rejected",
+        }
+    }
+}
+
+/// Location of the approvals of the crate names of a registry, next to its git metadata
+pub fn approvals_file(registry_uri: &str) -> PathBuf {
+    Path::new(registry_uri).join(".git").join("mirage-approvals")
+}
+
+/// Whether a name follows the rules of crate names: up to 64 ASCII alphanumerics, `-` or `_`
+pub fn is_valid_crate_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Reads the `name state` pairs of an approvals file. A missing file has no approvals,
+/// invalid lines are left out so they don't hide the other decisions.
+pub fn read_approvals(path: &Path) -> io::Result<BTreeMap<String, Approval>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut approvals = BTreeMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        match (fields.next().filter(|name| is_valid_crate_name(name)), fields.next().and_then(Approval::parse)) {
+            (Some(name), Some(approval)) => {
+                approvals.insert(String::from(name), approval);
+            },
+            _ => eprintln!("Ignoring invalid approval entry in {:?}: {}", path, line),
+        }
+    }
+    Ok(approvals)
+}
+
+fn write_approvals(file: &mut File, approvals: &BTreeMap<String, Approval>) -> io::Result<()> {
+    approvals.iter()
+        .try_for_each(|(name, approval)| writeln!(file, "{} {}", name, approval.as_str()))
+}
+
+/// Approves or rejects crate names, whether they are pending or not
+pub fn decide(registry_uri: &str, names: &[String], approval: Approval) -> io::Result<()> {
+    if let Some(name) = names.iter().find(|name| !is_valid_crate_name(name)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid crate name {:?}", name)));
+    }
+    let path = approvals_file(registry_uri);
+    let _lock = reloadable::lock_for_update(&path)?;
+    let mut approvals = read_approvals(&path)?;
+    for name in names {
+        approvals.insert(name.to_lowercase(), approval);
+    }
+    reloadable::write_atomically(&path, |file| write_approvals(file, &approvals))
+}
+
+pub fn pending(registry_uri: &str) -> io::Result<Vec<String>> {
+    read_approvals(&approvals_file(registry_uri)).map(|approvals| approvals.into_iter()
+        .filter(|(_, approval)| *approval == Approval::Pending)
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Approvals of the crate names of a registry, reloaded whenever the approvals file is modified.
+/// Crate names are compared case-insensitively, as in the index.
+pub struct ApprovalList {
+    approvals: ReloadableFile<BTreeMap<String, Approval>>,
+}
+
+impl ApprovalList {
+    pub fn new(registry_uri: &str) -> ApprovalList {
+        ApprovalList {
+            approvals: ReloadableFile::new(approvals_file(registry_uri), read_approvals),
+        }
+    }
+
+    pub fn reload(&self) {
+        self.approvals.reload_if_modified();
+    }
+
+    pub fn approval(&self, name: &str) -> Option<Approval> {
+        self.approvals.with(|approvals| approvals.get(&name.to_lowercase()).cloned())
+    }
+
+    pub fn is_approved(&self, name: &str) -> bool {
+        self.approval(name) == Some(Approval::Approved)
+    }
+
+    /// Queues the crate names without a decision as pending, approves those which were mirrored before
+    pub fn queue_new<F>(&self, names: Vec<String>, mirrored_before: F) -> io::Result<()>
+        where F: Fn(&str) -> bool {
+        // Decisions made since the last reload, or while queueing, must not be overwritten
+        let _lock = reloadable::lock_for_update(self.approvals.path())?;
+        self.reload();
+        let mut approvals = self.approvals.with(BTreeMap::clone);
+        let mut queued = false;
+        for name in names {
+            if let Entry::Vacant(entry) = approvals.entry(name.to_lowercase()) {
+                if mirrored_before(&name) {
+                    entry.insert(Approval::Approved);
+                } else {
+                    println!("Crate {} is pending approval", name);
+                    entry.insert(Approval::Pending);
+                }
+                queued = true;
+            }
+        }
+        if queued {
+            self.approvals.replace(approvals, write_approvals)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    #[test]
+    fn approve_new_crates() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-approvals-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let uri = dir.to_str().unwrap();
+        let approvals = ApprovalList::new(uri);
+
+        let names = vec![String::from("serde"), String::from("Tokio"), String::from("evil")];
+        approvals.queue_new(names, |name| name == "serde").unwrap();
+        assert!(approvals.is_approved("serde"));
+        assert_eq!(Some(Approval::Pending), approvals.approval("tokio"));
+        assert_eq!(vec![String::from("evil"), String::from("tokio")], pending(uri).unwrap());
+
+        decide(uri, &[String::from("tokio")], Approval::Approved).unwrap();
+        decide(uri, &[String::from("evil")], Approval::Rejected).unwrap();
+        assert_eq!(Some(Approval::Pending), approvals.approval("tokio"));
+        approvals.queue_new(vec![String::from("evil")], |_| true).unwrap();
+        assert!(approvals.is_approved("Tokio"));
+        assert_eq!(Some(Approval::Rejected), approvals.approval("evil"));
+        assert!(pending(uri).unwrap().is_empty());
+
+        let error = decide(uri, &[String::from("a b")], Approval::Approved).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert!(decide(uri, &["a".repeat(65)], Approval::Approved).is_err());
+        fs::write(approvals_file(uri), "serde approved\na b approved\ntokio maybe\n").unwrap();
+        let approvals = read_approvals(&approvals_file(uri)).unwrap();
+        assert_eq!(vec![(String::from("serde"), Approval::Approved)], approvals.into_iter().collect::<Vec<_>>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serialize_concurrent_decisions() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-concurrent-approvals-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let uri = dir.to_str().unwrap().to_string();
+        let deciding = (0..8).map(|i| {
+            let uri = uri.clone();
+            thread::spawn(move || (0..10).for_each(|j| {
+                decide(&uri, &[format!("crate-{}-{}", i, j)], Approval::Approved).unwrap();
+            }))
+        }).collect::<Vec<_>>();
+        let approvals = ApprovalList::new(&uri);
+        approvals.queue_new((0..50).map(|i| format!("new-{}", i)).collect(), |_| false).unwrap();
+        deciding.into_iter().for_each(|decisions| decisions.join().unwrap());
+        assert_eq!(130, read_approvals(&approvals_file(&uri)).unwrap().len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
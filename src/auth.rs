@@ -0,0 +1,189 @@
+use actix_web::{HttpRequest, HttpResponse, HttpMessage, Result, http::header};
+use actix_web::middleware::{Middleware, Started};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use sha2::{Sha256, Digest};
+use rand::{Rng, OsRng};
+use hex;
+use reloadable;
+
+/// Name of the token which authenticated a request, available in the request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthenticatedToken(pub String);
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn generate_token() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(format!("cio{}", hex::encode(bytes)))
+}
+
+/// Reads the `name hash` pairs from a token file. A missing token file contains no tokens.
+pub fn read_tokens(token_file: &str) -> io::Result<Vec<(String, String)>> {
+    let file = match File::open(token_file) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    BufReader::new(file)
+    .lines()
+    .filter(|line| line.as_ref().map(|line| !line.trim().is_empty()).unwrap_or(true))
+    .map(|line| line.and_then(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(name), Some(hash)) => Ok((String::from(name), String::from(hash))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid token entry: {}", line))),
+        }
+    }))
+    .collect()
+}
+
+/// Replaces the token file at once, so the server never reads it half-written
+fn write_tokens(token_file: &str, tokens: &[(String, String)]) -> io::Result<()> {
+    reloadable::write_private_atomically(Path::new(token_file), |file| tokens.iter()
+        .try_for_each(|(name, hash)| writeln!(file, "{} {}", name, hash)))
+}
+
+/// Generates a new token for `name`. Only its hash is stored, so the token can only be shown once.
+pub fn add_token(token_file: &str, name: &str) -> io::Result<String> {
+    let mut tokens = read_tokens(token_file)?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid token name: {:?}", name)));
+    }
+    if tokens.iter().any(|(existing, _)| existing == name) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Token {} already exists", name)));
+    }
+    let token = generate_token()?;
+    tokens.push((String::from(name), hash_token(&token)));
+    write_tokens(token_file, &tokens).map(|()| token)
+}
+
+/// Removes the token for `name`, returns whether it existed
+pub fn revoke_token(token_file: &str, name: &str) -> io::Result<bool> {
+    let tokens = read_tokens(token_file)?;
+    let remaining = tokens.iter()
+        .filter(|(existing, _)| existing != name)
+        .cloned()
+        .collect::<Vec<_>>();
+    if remaining.len() == tokens.len() {
+        return Ok(false);
+    }
+    write_tokens(token_file, &remaining).map(|()| true)
+}
+
+pub fn list_tokens(token_file: &str) -> io::Result<Vec<String>> {
+    read_tokens(token_file).map(|tokens| tokens.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Token hashes of a token file, reloaded whenever the file is modified
+pub struct TokenStore {
+    token_file: String,
+    tokens: RwLock<(Option<SystemTime>, HashMap<String, String>)>,
+}
+
+impl TokenStore {
+    pub fn new(token_file: &str) -> TokenStore {
+        TokenStore {
+            token_file: String::from(token_file),
+            tokens: RwLock::new((None, HashMap::new())),
+        }
+    }
+
+    fn reload_if_modified(&self) {
+        let modified = fs::metadata(&self.token_file).and_then(|metadata| metadata.modified()).ok();
+        let outdated = self.tokens.read().map(|tokens| tokens.0 != modified).unwrap_or(true);
+        if outdated {
+            match read_tokens(&self.token_file) {
+                Ok(tokens) => {
+                    let hashes = tokens.into_iter().map(|(name, hash)| (hash, name)).collect();
+                    if let Ok(mut current) = self.tokens.write() {
+                        *current = (modified, hashes);
+                    }
+                },
+                Err(e) => eprintln!("Could not read token file {}: {:?}", self.token_file, e),
+            }
+        }
+    }
+
+    /// Returns the name of the token, if it is valid
+    pub fn authenticate(&self, token: &str) -> Option<String> {
+        self.reload_if_modified();
+        let hash = hash_token(token);
+        self.tokens.read().ok().and_then(|tokens| tokens.1.get(&hash).cloned())
+    }
+}
+
+/// Middleware rejecting requests without a valid token in the `Authorization` header
+pub struct TokenAuth {
+    tokens: Arc<TokenStore>,
+    admins: Option<Vec<String>>,
+}
+
+impl TokenAuth {
+    pub fn new(tokens: Arc<TokenStore>) -> TokenAuth {
+        TokenAuth { tokens, admins: None }
+    }
+
+    /// Only accepts the tokens named in `admins`, other valid tokens are forbidden
+    pub fn admins(tokens: Arc<TokenStore>, admins: Vec<String>) -> TokenAuth {
+        TokenAuth { tokens, admins: Some(admins) }
+    }
+}
+
+impl<S> Middleware<S> for TokenAuth {
+    fn start(&self, req: &mut HttpRequest<S>) -> Result<Started> {
+        let token_name = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim_start_matches("Bearer ").trim())
+            .and_then(|token| self.tokens.authenticate(token));
+        match token_name {
+            Some(ref name) if self.admins.as_ref().map(|admins| !admins.contains(name)).unwrap_or(false) =>
+                Ok(Started::Response(HttpResponse::Forbidden().finish())),
+            Some(name) => {
+                req.extensions_mut().insert(AuthenticatedToken(name));
+                Ok(Started::Done)
+            },
+            None => Ok(Started::Response(HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Cargo login_url=\"\"")
+                .finish())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn manage_token_file() {
+        let token_file = env::temp_dir().join("cargo-mirage-manage-token-file");
+        let token_file = token_file.to_str().unwrap();
+        let _ = fs::remove_file(token_file);
+
+        let ci_token = add_token(token_file, "ci").unwrap();
+        let dev_token = add_token(token_file, "dev").unwrap();
+        assert_ne!(ci_token, dev_token);
+        assert!(add_token(token_file, "ci").is_err());
+        assert_eq!(vec![String::from("ci"), String::from("dev")], list_tokens(token_file).unwrap());
+        assert_eq!(0o600, fs::metadata(token_file).unwrap().permissions().mode() & 0o777);
+
+        let tokens = TokenStore::new(token_file);
+        assert_eq!(Some(String::from("ci")), tokens.authenticate(&ci_token));
+        assert_eq!(None, tokens.authenticate("unknown"));
+
+        assert!(revoke_token(token_file, "ci").unwrap());
+        assert!(!revoke_token(token_file, "ci").unwrap());
+        assert_eq!(vec![String::from("dev")], list_tokens(token_file).unwrap());
+        let _ = fs::remove_file(token_file);
+    }
+}
@@ -0,0 +1,171 @@
+use super::config;
+use glob::Pattern;
+use index_entry::{self, index_file_path, Dependency, DependencyKind, IndexEntry};
+use semver::{Version, VersionReq};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+
+/// Parses a root crate of the closure, e.g. `tokio@1`. A bare crate name matches all of its versions.
+pub fn parse_root(spec: &str) -> Result<(String, String), String> {
+    let mut parts = spec.trim().splitn(2, '@');
+    let name = parts.next().unwrap_or_default().trim().to_lowercase();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("Invalid crate name in {:?}", spec));
+    }
+    let req = parts.next().map(str::trim).unwrap_or("*");
+    VersionReq::parse(req).map_err(|e| format!("Invalid version requirement in {:?}: {:?}", spec, e))?;
+    Ok((name, String::from(req)))
+}
+
+/// Versions of a crate matching a requirement, with the features they are used with
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Requirement {
+    name: String,
+    req: String,
+    features: BTreeSet<String>,
+    default_features: bool,
+}
+
+/// Names of the optional dependencies enabled by the features of a requirement,
+/// and the features these features enable on the dependencies
+fn enabled_by_features(entry: &IndexEntry, requirement: &Requirement) -> (BTreeSet<String>, HashMap<String, BTreeSet<String>>) {
+    let mut enabled_deps = BTreeSet::new();
+    let mut dep_features = HashMap::<String, BTreeSet<String>>::new();
+    let mut pending = requirement.features.iter().cloned().collect::<Vec<_>>();
+    if requirement.default_features {
+        pending.push(String::from("default"));
+    }
+    let mut visited = BTreeSet::new();
+    while let Some(feature) = pending.pop() {
+        if !visited.insert(feature.clone()) {
+            continue;
+        }
+        let enables = entry.features.get(&feature)
+            .or_else(|| entry.features2.as_ref().and_then(|features2| features2.get(&feature)));
+        match enables {
+            Some(enables) => for value in enables {
+                if let Some(dep) = value.strip_prefix("dep:") {
+                    enabled_deps.insert(String::from(dep));
+                } else if let Some((dep, dep_feature)) = value.split_once('/') {
+                    // `dep?/feature` only enables the feature when the dependency is enabled otherwise
+                    match dep.strip_suffix('?') {
+                        Some(dep) => dep_features.entry(String::from(dep)).or_default().insert(String::from(dep_feature)),
+                        None => {
+                            enabled_deps.insert(String::from(dep));
+                            dep_features.entry(String::from(dep)).or_default().insert(String::from(dep_feature))
+                        },
+                    };
+                } else {
+                    pending.push(value.clone());
+                }
+            },
+            // Optional dependencies are implicit features, unless `dep:` is used
+            None => {
+                enabled_deps.insert(feature);
+            },
+        }
+    }
+    (enabled_deps, dep_features)
+}
+
+fn follows(dependency: &Dependency, enabled_deps: &BTreeSet<String>, closure: &config::Closure, targets: &[Pattern]) -> bool {
+    // Dependencies of other registries aren't in this index
+    dependency.registry.is_none()
+        && (dependency.kind() != DependencyKind::Dev || closure.dev)
+        && (!dependency.optional || closure.optional || enabled_deps.contains(&dependency.name))
+        && dependency.target.as_ref().map(|target| targets.iter().any(|pattern| pattern.matches(target))).unwrap_or(true)
+}
+
+/// Resolves the versions needed to build any version of the roots matching their requirement, from the local index.
+/// All versions matching a dependency requirement are included, as any of them may be picked without a lock file.
+/// Yanked versions are left out, as they aren't picked either.
+pub fn resolve(registry_uri: &str, roots: &[(String, String)], closure: &config::Closure) -> io::Result<Vec<IndexEntry>> {
+    let targets = closure.targets.iter()
+        .map(|pattern| Pattern::new(pattern).unwrap_or_else(|e| panic!("Invalid target pattern {}: {:?}", pattern, e)))
+        .collect::<Vec<_>>();
+    let mut index_files = HashMap::<String, Vec<IndexEntry>>::new();
+    let mut resolved = BTreeMap::<(String, String), IndexEntry>::new();
+    let mut visited = BTreeSet::new();
+    let mut pending = roots.iter()
+        .map(|(name, req)| Requirement { name: name.to_lowercase(), req: req.clone(), features: BTreeSet::new(), default_features: true })
+        .collect::<Vec<_>>();
+
+    while let Some(requirement) = pending.pop() {
+        if !visited.insert(requirement.clone()) {
+            continue;
+        }
+        let req = match VersionReq::parse(&requirement.req) {
+            Ok(req) => req,
+            Err(e) => {
+                eprintln!("Skipping invalid requirement {} {:?}: {:?}", requirement.name, requirement.req, e);
+                continue;
+            },
+        };
+        if !index_files.contains_key(&requirement.name) {
+            // A name which can't be a crate name isn't in the index either
+            let entries = match index_file_path(&requirement.name)
+                .map(|index_file| index_entry::read_index_file(&Path::new(registry_uri).join(index_file)))
+                .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::NotFound, "Not a crate name"))) {
+                Ok(entries) => entries,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("Crate {} is not in the index", requirement.name);
+                    Vec::new()
+                },
+                Err(e) => return Err(e),
+            };
+            index_files.insert(requirement.name.clone(), entries);
+        }
+        let matching = index_files[&requirement.name].iter()
+            .filter(|entry| !entry.yanked)
+            .filter(|entry| Version::parse(&entry.vers).map(|version| req.matches(&version)).unwrap_or(false));
+        for entry in matching {
+            let (enabled_deps, dep_features) = enabled_by_features(entry, &requirement);
+            for dependency in entry.deps.iter().filter(|dependency| follows(dependency, &enabled_deps, closure, &targets)) {
+                let mut features = dependency.features.iter().cloned().collect::<BTreeSet<_>>();
+                features.extend(dep_features.get(&dependency.name).into_iter().flatten().cloned());
+                pending.push(Requirement {
+                    name: dependency.crate_name().to_lowercase(),
+                    req: dependency.req.clone(),
+                    features,
+                    default_features: dependency.default_features,
+                });
+            }
+            resolved.insert((entry.name.to_lowercase(), entry.vers.clone()), entry.clone());
+        }
+    }
+
+    if let Some((name, req)) = roots.iter().find(|(name, _)| !resolved.keys().any(|(resolved_name, _)| resolved_name == &name.to_lowercase())) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No version of {} matches {:?}", name, req)));
+    }
+    Ok(resolved.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn resolved_versions(roots: &[&str], closure: &config::Closure) -> Vec<String> {
+        let roots = roots.iter().map(|root| parse_root(root).unwrap()).collect::<Vec<_>>();
+        resolve("test/closure", &roots, closure).unwrap().into_iter()
+            .map(|entry| format!("{}@{}", entry.name, entry.vers))
+            .collect()
+    }
+
+    #[test]
+    fn resolve_dependency_closure() {
+        assert_eq!(
+            vec!["app@1.0.0", "core2@0.1.0", "feat@1.0.0", "lib@1.0.0", "opt@1.0.0", "speedy@1.0.0"],
+            resolved_versions(&["app@1"], &config::Closure::default()));
+        let everything = config::Closure{ optional: true, dev: true, targets: vec![String::from("cfg(windows)")] };
+        assert_eq!(
+            vec!["app@1.0.0", "core2@0.1.0", "devtool@1.0.0", "extra@1.0.0", "feat@1.0.0", "lib@1.0.0", "opt@1.0.0", "speedy@1.0.0", "winapi@0.3.9"],
+            resolved_versions(&["app@1"], &everything));
+        assert_eq!(
+            vec!["feat@1.0.0", "lib@1.0.0", "lib@2.0.0", "speedy@1.0.0"],
+            resolved_versions(&["lib"], &config::Closure::default()));
+        assert!(resolve("test/closure", &[parse_root("missing").unwrap()], &config::Closure::default()).is_err());
+        assert!(parse_root("tokio@not a version").is_err());
+    }
+}
@@ -1,14 +1,17 @@
-use git2::{Repository, Direction, Signature, Commit, Error, ObjectType, BranchType, MergeAnalysis, AnnotatedCommit};
+use git2::{Repository, Signature, Commit, Error, ErrorCode, ObjectType, BranchType, MergeAnalysis, AnnotatedCommit, Oid, Index, Tree};
 use super::config;
-use std::thread;
 use std::path::Path;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, SystemTime};
+use schedule::{self, CronSchedule, SyncTrigger};
+use supervisor::Supervisor;
 use git_utils;
-use std::fs::OpenOptions;
+use proxy;
+use quarantine;
+use snapshots;
+use std::fs::{self, File, OpenOptions};
 use serde_json;
 
-const OFFICIAL_CRATES_REGISTRY : &str = "https://github.com/rust-lang/crates.io-index.git";
 const CARGO_SIG_AUTHOR : &str = "Cargo mirage";
 const CARGO_SIG_EMAIL : &str = "cargo@mirage.io";
 
@@ -16,6 +19,12 @@ const CARGO_SIG_EMAIL : &str = "cargo@mirage.io";
 struct CratesIOConfig {
     pub dl: String,
     pub api: String,
+    #[serde(rename = "auth-required", default, skip_serializing_if = "is_false")]
+    pub auth_required: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 enum MergeAction<'a> {
@@ -24,11 +33,12 @@ enum MergeAction<'a> {
     Nop,
 }
 
-fn merge_analysis_to_action<'a>(merge_analysis: MergeAnalysis, commit: AnnotatedCommit<'a>) -> MergeAction {
-    if merge_analysis.contains(MergeAnalysis::ANALYSIS_FASTFORWARD) {
+/// Fast-forwards are only possible when no upstream file needs to be dropped
+fn merge_analysis_to_action<'a>(merge_analysis: MergeAnalysis, commit: AnnotatedCommit<'a>, allow_fast_forward: bool) -> MergeAction<'a> {
+    if merge_analysis.contains(MergeAnalysis::ANALYSIS_FASTFORWARD) && allow_fast_forward {
         println!("Fast-forward merge of remote changes");
         MergeAction::FastForward
-    } else if merge_analysis.contains(MergeAnalysis::ANALYSIS_NORMAL) {
+    } else if merge_analysis.intersects(MergeAnalysis::ANALYSIS_FASTFORWARD | MergeAnalysis::ANALYSIS_NORMAL) {
         println!("Merging remote changes");
         MergeAction::Normal(commit)
     } else {
@@ -37,36 +47,55 @@ fn merge_analysis_to_action<'a>(merge_analysis: MergeAnalysis, commit: Annotated
     }
 }
 
-fn find_head_commit(repo: &Repository) -> Result<Commit, Error> {
+fn find_head_commit(repo: &Repository) -> Result<Commit<'_>, Error> {
     let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     obj.into_commit().map_err(|_| Error::from_str("Couldn't find commit"))
 }
 
-fn find_remote_master_tip(repo : &Repository) -> Result<Commit, Error> {
+fn find_remote_master_tip(repo : &Repository) -> Result<Commit<'_>, Error> {
     repo.find_branch("origin/master", BranchType::Remote)
         .and_then(|branch| branch.into_reference().peel(ObjectType::Commit))
         .and_then(|obj| obj.into_commit()
             .map_err(|e| Error::from_str(format!("Could find remote master tip: {:?}", e).as_str())))
 }
 
-fn merge_upstream_master(repo: &Repository) {
-    git_utils::clean_working_dir(repo)
+/// Fast-forwards to the remote commit, with the custom configuration committed on top.
+/// The branch moves straight to the configuration commit, so it never lacks the custom configuration.
+fn fast_forward_with_config(repo: &Repository, remote_id: Oid, new_config: &CratesIOConfig) -> Result<(), Error> {
+    let remote_commit = repo.find_commit(remote_id)?;
+    let config_json = serde_json::to_vec(new_config)
+        .map_err(|e| Error::from_str(format!("Could not serialize config.json: {:?}", e).as_str()))?;
+    let signature = Signature::now(CARGO_SIG_AUTHOR, CARGO_SIG_EMAIL)?;
+    git_utils::commit_file_onto(repo, &remote_commit, "config.json", &config_json, &signature, "API mirror as configuration")
+        .and_then(|config_commit_id| git_utils::fast_forward_merge(repo, config_commit_id))
+}
+
+fn merge_upstream_master(repo: &Repository, registry_config: &config::CrateRegistry, new_config: &CratesIOConfig) -> Result<(), Error> {
+    let ours = find_head_commit(repo)?.tree()?;
+    let merged = git_utils::clean_working_dir(repo)
     .and_then(|()| find_remote_master_tip(repo))
     .and_then(|remote_commit| repo.find_annotated_commit(remote_commit.id()))
     .and_then(|remote| {
         repo.merge_analysis(&[&remote])
-            .map(|(analysis, _)| (remote.id(), merge_analysis_to_action(analysis, remote)))
+            .map(|(analysis, _)| (remote.id(), merge_analysis_to_action(analysis, remote, registry_config.reserved.is_empty())))
     })
     .and_then(|(remote_id, action)| {
         match action {
-            MergeAction::FastForward => git_utils::fast_forward_merge(&repo, remote_id),
-            MergeAction::Nop => Ok(None),
-            MergeAction::Normal(remote_commit) => git_utils::force_merge_remote_commit(&repo, remote_commit),
+            MergeAction::FastForward => fast_forward_with_config(repo, remote_id, new_config).map(|()| None),
+            MergeAction::Nop => {
+                // Reserved crates may already be in the index, e.g. in a fresh clone
+                if drop_reserved_upstream_files(repo, registry_config, &ours)? {
+                    commit_index(repo, "Drop reserved crates from upstream")?;
+                }
+                Ok(None)
+            },
+            MergeAction::Normal(remote_commit) => git_utils::force_merge_remote_commit(repo, remote_commit)
+                .and_then(|remote_commit| drop_reserved_upstream_files(repo, registry_config, &ours).map(|_| remote_commit)),
         }
     })
     .and_then(|annotated_remote_commit_opt| {
         match annotated_remote_commit_opt {
-            Some(annotated_remote_commit) => repo.find_commit(annotated_remote_commit.id()).map(|x| Some(x)),
+            Some(annotated_remote_commit) => repo.find_commit(annotated_remote_commit.id()).map(Some),
             None => Ok(None),
         }
     })
@@ -79,7 +108,7 @@ fn merge_upstream_master(repo: &Repository) {
                 .and_then(|tree| {
                     let signature = Signature::now(CARGO_SIG_AUTHOR, CARGO_SIG_EMAIL)
                         .expect("Could not create signature");
-                    let parent_commit = find_head_commit(&repo)?;
+                    let parent_commit = find_head_commit(repo)?;
                     repo.commit(Some("HEAD"), //  point HEAD to our new commit
                         &signature, // author
                         &signature, // committer
@@ -92,124 +121,612 @@ fn merge_upstream_master(repo: &Repository) {
             None => Ok(()),
         }
     })
-    .and_then(|_| git_utils::clean_working_dir(repo))
-    .unwrap_or_else(|e| eprintln!("Could not merge remote master: {:?}", e));
+    .and_then(|_| git_utils::clean_working_dir(repo));
+
+    repo.cleanup_state().expect("Couldn't clean-up state");
+    merged
+}
+
+/// Whether upstream history was squashed or force-pushed, leaving no common ancestor with the local history
+fn upstream_history_rewritten(repo: &Repository) -> Result<bool, Error> {
+    let head_commit = find_head_commit(repo)?;
+    let remote_commit = find_remote_master_tip(repo)?;
+    match repo.merge_base(head_commit.id(), remote_commit.id()) {
+        Ok(_) => Ok(false),
+        Err(ref e) if e.code() == ErrorCode::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Crate of an index file, e.g. "serde" for "se/rd/serde". Files at the root of the index aren't crates.
+fn index_file_crate(path: &str) -> Option<&str> {
+    if path.starts_with('.') {
+        return None;
+    }
+    path.rfind('/').map(|separator| &path[separator + 1..])
+}
+
+fn reserved_index_files(index: &Index, registry_config: &config::CrateRegistry) -> Vec<String> {
+    index.iter()
+        .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+        .filter(|path| index_file_crate(path).map(|name| registry_config.is_reserved(name)).unwrap_or(false))
+        .collect()
+}
 
-    repo.cleanup_state().expect("Couldn't clean-up state")
+/// Drops the upstream index files of reserved crates from the working directory & git index.
+/// The local index files of reserved crates which differ from upstream are private crates,
+/// they are restored from `ours` when upstream overwrote them. Returns whether any file changed.
+fn drop_reserved_upstream_files(repo: &Repository, registry_config: &config::CrateRegistry, ours: &Tree) -> Result<bool, Error> {
+    if registry_config.reserved.is_empty() {
+        return Ok(false);
+    }
+    let upstream = find_remote_master_tip(repo)?.tree()?;
+    let mut index = repo.index()?;
+    let mut ours_index = Index::new()?;
+    ours_index.read_tree(ours)?;
+    let mut paths = reserved_index_files(&index, registry_config);
+    paths.extend(reserved_index_files(&ours_index, registry_config));
+    paths.sort();
+    paths.dedup();
+
+    let to_io_error = |e: std::io::Error| Error::from_str(format!("Could not update reserved index file: {:?}", e).as_str());
+    let mut changed = false;
+    for path in paths {
+        let staged = index.get_path(Path::new(&path), 0).map(|entry| entry.id);
+        let upstream_id = upstream.get_path(Path::new(&path)).ok().map(|entry| entry.id());
+        let private = ours.get_path(Path::new(&path)).ok().map(|entry| entry.id())
+            .filter(|local_id| Some(*local_id) != upstream_id);
+        if staged == private {
+            continue;
+        }
+        let file_path = Path::new(&registry_config.uri).join(&path);
+        match private {
+            Some(private_id) => {
+                eprintln!("ALERT: upstream publishes reserved crate {} of {}, keeping the private index file", path, registry_config.name);
+                let blob = repo.find_blob(private_id)?;
+                if let Some(parent) = file_path.parent() {
+                    fs::create_dir_all(parent).map_err(to_io_error)?;
+                }
+                fs::write(&file_path, blob.content()).map_err(to_io_error)?;
+                index.add_path(Path::new(&path))?;
+            },
+            None => {
+                eprintln!("ALERT: upstream publishes reserved crate {} of {}, dropping its index file", path, registry_config.name);
+                if file_path.exists() {
+                    fs::remove_file(&file_path).map_err(to_io_error)?;
+                }
+                index.remove_path(Path::new(&path))?;
+            },
+        }
+        changed = true;
+    }
+    index.write()?;
+    Ok(changed)
 }
 
+/// Restarts the local history from the upstream tip, the custom configuration is committed on top afterwards.
+/// Private index files of reserved crates are carried over.
+fn rebuild_from_upstream_master(repo: &Repository, registry_config: &config::CrateRegistry, prune: bool) -> Result<(), Error> {
+    let ours = find_head_commit(repo)?.tree()?;
+    let remote_commit = find_remote_master_tip(repo)?;
+    git_utils::reset_to_commit(repo, &remote_commit)?;
+    drop_reserved_upstream_files(repo, registry_config, &ours)?;
+
+    if prune {
+        git_utils::prune_unreachable_objects(repo)
+            .unwrap_or_else(|e| eprintln!("Could not prune the old history: {:?}", e));
+    }
+    Ok(())
+}
+
+/// Configuration of the working directory, `None` when it is missing or invalid, e.g. before the first sync
 fn read_config_from_file(registry_uri: &str) -> Option<CratesIOConfig> {
     let config_json_path = Path::new(registry_uri).join("config.json");
-    let read_file = OpenOptions::new().read(true).open(config_json_path)
-        .expect("Could not read config.json");
+    let read_file = File::open(config_json_path).ok()?;
     serde_json::from_reader(&read_file).ok()
 }
 
-fn write_config_to_file(config: &CratesIOConfig, registry_uri: &str) -> Result<(), serde_json::Error> {
+fn write_config_to_file(config: &CratesIOConfig, registry_uri: &str) -> Result<(), Error> {
     let config_json_path = Path::new(registry_uri).join("config.json");
-    let write_file = OpenOptions::new().write(true).create(true).truncate(true).open(config_json_path)
-            .expect("Could not config.json for writing");
-    serde_json::to_writer(&write_file, &config)
+    OpenOptions::new().write(true).create(true).truncate(true).open(config_json_path)
+        .map_err(|e| e.to_string())
+        .and_then(|write_file| serde_json::to_writer(&write_file, &config).map_err(|e| e.to_string()))
+        .map_err(|e| Error::from_str(format!("Could not write config.json: {}", e).as_str()))
 }
 
-fn add_custom_config(repo: &Repository, registry_uri: &str, public_interface: &str) {
-    let new_config = CratesIOConfig{ 
-        api: String::from("https://crates.io/"),    
-        dl: String::from(public_interface),
-    };
+fn mirror_config(public_url: &str, auth_required: bool) -> CratesIOConfig {
+    CratesIOConfig{
+        api: String::from(public_url),
+        dl: String::from(public_url),
+        auth_required,
+    }
+}
+
+fn is_current_config(registry_uri: &str, new_config: &CratesIOConfig) -> bool {
+    read_config_from_file(registry_uri)
+        .map(|current_config| current_config == *new_config)
+        .unwrap_or(false)
+}
+
+fn add_custom_config(repo: &Repository, registry_uri: &str, new_config: &CratesIOConfig) -> Result<(), Error> {
+    if !is_current_config(registry_uri, new_config) {
+        write_config_to_file(new_config, registry_uri)?;
+        commit_custom_config(repo)?;
+    }
+    Ok(())
+}
 
-    let current_config_opt = read_config_from_file(registry_uri);
-    let equal = current_config_opt
-        .map(|current_config| current_config == new_config)
+/// Keeps the local history equal to upstream, plus a single commit with the custom configuration.
+/// The configuration is committed even when upstream has the same one, so HEAD's parent is always the upstream tip.
+fn track_upstream_master(repo: &Repository, registry_config: &config::CrateRegistry, new_config: &CratesIOConfig) -> Result<(), Error> {
+    let registry_uri = &registry_config.uri;
+    let on_remote_master = find_head_commit(repo)
+        .and_then(|head_commit| {
+            let remote_commit = find_remote_master_tip(repo)?;
+            Ok(head_commit.parent_ids().collect::<Vec<_>>() == vec![remote_commit.id()])
+        })
         .unwrap_or(false);
 
-    if !equal {
-        write_config_to_file(&new_config, registry_uri).expect("Could not write config.json");
-        commit_custom_config(repo).expect("Could not commit config.json");
+    if !on_remote_master || !is_current_config(registry_uri, new_config) {
+        println!("Resetting to remote master");
+        let prune = registry_config.prune_rewritten_history && upstream_history_rewritten(repo)?;
+        rebuild_from_upstream_master(repo, registry_config, prune)?;
+        if !is_current_config(registry_uri, new_config) {
+            write_config_to_file(new_config, registry_uri)?;
+        }
+        commit_custom_config(repo)?;
+    } else {
+        println!("Repo is up-to-date");
     }
+    Ok(())
 }
 
 fn commit_custom_config(repo: &Repository) -> Result<(), Error> {
+    repo.index()
+    .and_then(|mut index| index.add_path(Path::new("config.json")))
+    .and_then(|()| commit_index(repo, "API mirror as configuration"))
+}
+
+/// Commits the git index on top of HEAD
+fn commit_index(repo: &Repository, message: &str) -> Result<(), Error> {
     let mut index = repo.index()?;
 
-    index.add_path(Path::new("config.json"))
-    .and_then(|()| index.write_tree())
+    index.write_tree()
     .and_then(|oid| { repo.find_tree(oid) })
     .and_then(|tree| {
-        let signature = Signature::now(CARGO_SIG_AUTHOR, CARGO_SIG_EMAIL)
-            .expect("Could not create signature");
-        let parent_commit = find_head_commit(&repo)?;
+        let signature = Signature::now(CARGO_SIG_AUTHOR, CARGO_SIG_EMAIL)?;
+        let parent_commit = find_head_commit(repo)?;
         repo.commit(Some("HEAD"), //  point HEAD to our new commit
             &signature, // author
             &signature, // committer
-            "API mirror as configuration", // commit message
+            message, // commit message
             &tree, // tree
             &[&parent_commit]) // parents
     })
     .and_then(|_| git_utils::clean_working_dir(repo))
 }
 
+fn fetch_upstream_master(repo: &Repository, registry_config: &config::CrateRegistry) -> Result<(), Error> {
+    println!("Fetching remote repository of {}", registry_config.name);
+    git_utils::fetch_origin_branch(repo, registry_config.upstream(), "master", &registry_config.git)?;
+    println!("Fetch of {} complete", registry_config.name);
+    Ok(())
+}
+
+fn sync_with_upstream_master(repo: &Repository, registry_config: &config::CrateRegistry, new_config: &CratesIOConfig) -> Result<(), Error> {
+    if registry_config.track_upstream {
+        track_upstream_master(repo, registry_config, new_config)
+    } else {
+        // Try to merge upstream
+        let synced = upstream_history_rewritten(repo)
+            .and_then(|rewritten| if rewritten {
+                println!("Upstream history of {} was rewritten, rebuilding the local history", registry_config.name);
+                rebuild_from_upstream_master(repo, registry_config, registry_config.prune_rewritten_history)
+            } else {
+                merge_upstream_master(repo, registry_config, new_config)
+            });
+        let configured = add_custom_config(repo, &registry_config.uri, new_config);
+        synced.and(configured)
+    }
+}
+
+/// Fetches & syncs the index with upstream, then records the new versions & snapshots it.
+/// Recording & snapshotting are best-effort, a failed fetch or sync fails the update.
+fn update_registry(
+    repo: &Repository,
+    registry_config: &config::CrateRegistry,
+    new_config: &CratesIOConfig) -> Result<(), Error> {
+    fetch_upstream_master(repo, registry_config)?;
+    sync_with_upstream_master(repo, registry_config, new_config)?;
+
+    if let Some(ref quarantine) = registry_config.quarantine {
+        quarantine::record_first_seen(repo, &registry_config.uri, quarantine, SystemTime::now())
+            .unwrap_or_else(|e| eprintln!("Could not record new versions of {}: {:?}", registry_config.name, e));
+    }
+    if let Some(ref retention) = registry_config.snapshots {
+        snapshots::take_snapshot(repo, retention, SystemTime::now())
+            .unwrap_or_else(|e| eprintln!("Could not snapshot {}: {:?}", registry_config.name, e));
+    }
+    Ok(())
+}
+
+/// Time until the next scheduled sync, every `update_interval` without a schedule
+fn until_next_sync(registry_config: &config::CrateRegistry, schedule: &[CronSchedule]) -> Duration {
+    if schedule.is_empty() {
+        return Duration::from_secs(u64::from(registry_config.update_interval));
+    }
+    let now = SystemTime::now();
+    schedule::next_time(schedule, now)
+        .and_then(|next_sync| next_sync.duration_since(now).ok())
+        // Never on schedule, only on request
+        .unwrap_or_else(|| Duration::from_secs(86_400))
+}
+
+/// Syncs the index on schedule & on request until shutting down. While upstream is unavailable,
+/// the local index is served as it is & the sync is retried with a backoff.
 fn monitor_registry(
     repo: &Repository,
-    stop: mpsc::Receiver<()>,
-    download_crates: mpsc::Sender<()>,
-    registry_uri: &str,
-    interval: &u32,
-    public_crate_store_interface: &str) {
+    supervisor: &Supervisor,
+    trigger: &SyncTrigger,
+    schedule: &[CronSchedule],
+    download_crates: &mpsc::Sender<()>,
+    registry_config: &config::CrateRegistry,
+    new_config: &CratesIOConfig) {
+    let task = registry_task(registry_config);
     loop {
-        let mut remote = match repo.find_remote("origin") {
-            Ok(r) => r,
-            Err(_) => repo.remote("origin", &OFFICIAL_CRATES_REGISTRY).expect("Could add remote git repository"),
+        let wait = match update_registry(repo, registry_config, new_config) {
+            Ok(()) => {
+                supervisor.report_success(&task);
+                // Start downloading crates
+                download_crates.send(()).unwrap_or_else(|e| eprintln!("Could not trigger crates for download: {:?}", e));
+                until_next_sync(registry_config, schedule)
+            },
+            Err(e) => {
+                eprintln!("Could not sync {} with upstream, serving the local index: {}", registry_config.name, e);
+                let failures = supervisor.report_failure(&task, e.message().to_string());
+                supervisor.backoff(failures).min(until_next_sync(registry_config, schedule))
+            },
         };
-
-        remote.connect(Direction::Fetch).expect("Could not connect to remote repository");
-        println!("Fetching remote repository");
-        remote.fetch(&["master"], None, None).expect("Could not fetch from remote repository");
-        println!("Fetch complete");
-        remote.disconnect();
-
-        // Try to merge upstream
-        merge_upstream_master(repo);
-        add_custom_config(repo, registry_uri, public_crate_store_interface);
-
-        // Start downloading crates
-        download_crates.send(()).unwrap_or_else(|e| eprintln!("Could not trigger crates for download: {:?}", e));
-
-        let start_time = SystemTime::now();
-        loop {
-            // Check if we need to exit the monitoring loop
-            if let Ok(()) = stop.try_recv() {
-                break;
-            }
-
-            let waiting_time_over = SystemTime::now()
-            .duration_since(start_time)
-            .ok().map(|delta| delta > Duration::from_secs(*interval as u64))
-            .unwrap_or(false);
-            if waiting_time_over { break; }
-            thread::sleep(Duration::from_secs(5))
+        if !trigger.wait(wait, supervisor) {
+            break;
         }
     }
 }
 
-fn open_git_repo(uri: &str) -> Repository {
-    let repo = if Path::new(&uri).exists() {
-        Repository::open(uri)
+/// Serves the local index as it is, without ever fetching upstream
+fn serve_offline(
+    repo: &Repository,
+    supervisor: &Supervisor,
+    download_crates: &mpsc::Sender<()>,
+    registry_config: &config::CrateRegistry,
+    new_config: &CratesIOConfig) {
+    add_custom_config(repo, &registry_config.uri, new_config)
+        .unwrap_or_else(|e| eprintln!("Could not configure {} as a mirror: {}", registry_config.name, e.message()));
+    download_crates.send(()).unwrap_or_else(|e| eprintln!("Could not trigger crates for download: {:?}", e));
+    // Nothing to do until shutting down
+    while supervisor.sleep(Duration::from_secs(60)) {}
+}
+
+/// Opens the local index, cloning it from upstream first unless offline
+fn open_git_repo(registry_config: &config::CrateRegistry, http: &config::UpstreamHttp, offline: bool) -> Result<Repository, Error> {
+    let uri = &registry_config.uri;
+    let upstream_proxy = proxy::proxy_for(http, registry_config.upstream());
+    if offline {
+        return Repository::open(uri);
+    }
+    let repo = if Path::new(uri).exists() {
+        let repo = Repository::open(uri)?;
+        git_utils::set_origin_proxy(&repo, upstream_proxy.as_deref())?;
+        repo
     } else {
-        Repository::clone(OFFICIAL_CRATES_REGISTRY, uri)
+        git_utils::clone_repo(registry_config.upstream(), uri, &registry_config.git, upstream_proxy.as_deref())?
     };
-    repo.expect(&format!("Could not open repository: {}", &uri))
+    git_utils::set_stall_timeout(&repo, http.stall_timeout())?;
+    Ok(repo)
+}
+
+fn registry_task(registry_config: &config::CrateRegistry) -> String {
+    format!("registry {}", registry_config.name)
+}
+
+fn public_registry_url(registry_config: &config::CrateRegistry, crate_store_config: &config::CrateStore) -> String {
+    format!("{}{}", crate_store_config.public_url(), registry_config.prefix())
+}
+
+/// Clones or updates the index once, without monitoring it
+pub fn update(registry_config: &config::CrateRegistry, crate_store_config: &config::CrateStore, http: &config::UpstreamHttp) {
+    let repo = open_git_repo(registry_config, http, false)
+        .unwrap_or_else(|e| panic!("Could not open repository {}: {:?}", registry_config.uri, e));
+    let new_config = mirror_config(&public_registry_url(registry_config, crate_store_config), crate_store_config.auth.is_some());
+    update_registry(&repo, registry_config, &new_config)
+        .unwrap_or_else(|e| panic!("Could not sync {} with upstream: {}", registry_config.name, e));
 }
 
-pub fn start(registry_config: &config::CrateRegistry, crate_store_config: &config::CrateStore) -> (mpsc::Sender<()>, mpsc::Receiver<()>) {
+/// Monitors the index as a supervised task, which triggers the crawler after every sync
+pub fn start(
+    registry_config: &config::CrateRegistry,
+    crate_store_config: &config::CrateStore,
+    http: &config::UpstreamHttp,
+    supervisor: &Arc<Supervisor>) -> (mpsc::Receiver<()>, Arc<SyncTrigger>) {
     let registry_config = registry_config.clone();
-    let public_crate_store_interface = format!("http://{}:{}", crate_store_config.public_host, crate_store_config.port);
-    let (tx_monitoring, rx_monitoring) = mpsc::channel();
+    let http = http.clone();
+    let schedule = schedule::parse_schedule(&registry_config.schedule)
+        .unwrap_or_else(|e| panic!("Invalid schedule of {}: {}", registry_config.name, e));
+    let trigger = Arc::new(SyncTrigger::new());
+    let task_trigger = trigger.clone();
+    let new_config = mirror_config(&public_registry_url(&registry_config, crate_store_config), crate_store_config.auth.is_some());
+    let offline = crate_store_config.offline;
     let (tx_download_crates, rx_download_crates) = mpsc::channel();
 
-    thread::spawn(move || {
-        let repo = open_git_repo(&registry_config.uri);
-        monitor_registry(&repo, rx_monitoring, tx_download_crates, &registry_config.uri, &registry_config.update_interval, &public_crate_store_interface)
+    let task_supervisor = supervisor.clone();
+    supervisor.supervise(&registry_task(&registry_config), move || {
+        // Failing to clone the index fails the task, as there is nothing to serve yet
+        let repo = open_git_repo(&registry_config, &http, offline)
+            .unwrap_or_else(|e| panic!("Could not open repository {}: {}", registry_config.uri, e));
+        if offline {
+            serve_offline(&repo, &task_supervisor, &tx_download_crates, &registry_config, &new_config)
+        } else {
+            monitor_registry(&repo, &task_supervisor, &task_trigger, &schedule, &tx_download_crates, &registry_config, &new_config)
+        }
     });
-    (tx_monitoring, rx_download_crates)
-}
\ No newline at end of file
+    (rx_download_crates, trigger)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use git2::{IndexEntry, IndexTime};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    const UPSTREAM_CONFIG : &str = r#"{"dl":"https://crates.io/api/v1/crates","api":"https://crates.io"}"#;
+    const SERDE_ENTRY : &str = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#;
+    const TOKIO_ENTRY : &str = r#"{"name":"tokio","vers":"1.0.0","deps":[],"cksum":"bb","features":{},"yanked":false}"#;
+    const ACME_PRIVATE : &str = r#"{"name":"acme-tool","vers":"1.0.0","deps":[],"cksum":"cc","features":{},"yanked":false}"#;
+    const ACME_SQUATTED : &str = r#"{"name":"acme-tool","vers":"9.9.9","deps":[],"cksum":"dd","features":{},"yanked":false}"#;
+
+    fn new_config() -> CratesIOConfig {
+        mirror_config("http://mirror.local/registries/test", false)
+    }
+
+    /// Creates a bare upstream repository & the configuration of a registry mirroring it
+    fn setup(name: &str) -> (PathBuf, Repository, config::CrateRegistry) {
+        let dir = env::temp_dir().join(format!("cargo-mirage-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let upstream = Repository::init_bare(dir.join("upstream")).unwrap();
+        commit_upstream(&upstream, "config.json", UPSTREAM_CONFIG);
+        commit_upstream(&upstream, "serde", SERDE_ENTRY);
+
+        let mut registry = config::Configuration::default().crate_registry.remove(0);
+        registry.uri = String::from(dir.join("local").to_str().unwrap());
+        registry.upstream = Some(String::from(dir.join("upstream").to_str().unwrap()));
+        (dir, upstream, registry)
+    }
+
+    /// Commits a file to master of the upstream repository
+    fn commit_upstream(upstream: &Repository, file_path: &str, contents: &str) -> Oid {
+        let signature = Signature::now("upstream", "upstream@crates.io").unwrap();
+        let parent = upstream.refname_to_id("refs/heads/master").ok()
+            .map(|parent_id| upstream.find_commit(parent_id).unwrap());
+        let mut index = Index::new().unwrap();
+        if let Some(ref parent) = parent {
+            index.read_tree(&parent.tree().unwrap()).unwrap();
+        }
+        index.add(&IndexEntry{
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0, ino: 0, mode: 0o100_644, uid: 0, gid: 0,
+            file_size: contents.len() as u32,
+            id: upstream.blob(contents.as_bytes()).unwrap(),
+            flags: 0,
+            flags_extended: 0,
+            path: file_path.as_bytes().to_vec(),
+        }).unwrap();
+        let tree = upstream.find_tree(index.write_tree_to(upstream).unwrap()).unwrap();
+        let parents = parent.iter().collect::<Vec<_>>();
+        upstream.commit(Some("refs/heads/master"), &signature, &signature, file_path, &tree, &parents).unwrap()
+    }
+
+    /// Replaces the upstream history by a single commit, as when the index is squashed
+    fn squash_upstream(upstream: &Repository, file_path: &str, contents: &str) -> Oid {
+        let signature = Signature::now("upstream", "upstream@crates.io").unwrap();
+        let mut tree_builder = upstream.treebuilder(None).unwrap();
+        tree_builder.insert("config.json", upstream.blob(UPSTREAM_CONFIG.as_bytes()).unwrap(), 0o100_644).unwrap();
+        tree_builder.insert(file_path, upstream.blob(contents.as_bytes()).unwrap(), 0o100_644).unwrap();
+        let tree = upstream.find_tree(tree_builder.write().unwrap()).unwrap();
+        let squashed = upstream.commit(None, &signature, &signature, "Squash the index", &tree, &[]).unwrap();
+        upstream.reference("refs/heads/master", squashed, true, "squash").unwrap();
+        squashed
+    }
+
+    fn sync(local: &Repository, registry: &config::CrateRegistry) {
+        fetch_upstream_master(local, registry).unwrap();
+        sync_with_upstream_master(local, registry, &new_config()).unwrap();
+    }
+
+    #[test]
+    fn point_config_to_path_prefix() {
+        let configuration = config::Configuration::default();
+        let mut crate_store = configuration.crate_store;
+        crate_store.path_prefix = Some(String::from("/cargo/"));
+        let config_json = serde_json::to_string(&mirror_config(&public_registry_url(&configuration.crate_registry[0], &crate_store), false)).unwrap();
+        assert_eq!(r#"{"dl":"http://127.0.0.1:8080/cargo/registries/crates-io","api":"http://127.0.0.1:8080/cargo/registries/crates-io"}"#, config_json);
+    }
+
+    #[test]
+    fn fast_forward_upstream_changes() {
+        let (dir, upstream, registry) = setup("fast-forward");
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        let upstream_tip = commit_upstream(&upstream, "tokio", TOKIO_ENTRY);
+
+        sync(&local, &registry);
+
+        let head_commit = find_head_commit(&local).unwrap();
+        assert_eq!(Some("API mirror as configuration"), head_commit.message());
+        assert_eq!(vec![upstream_tip], head_commit.parent_ids().collect::<Vec<_>>());
+        assert!(is_current_config(&registry.uri, &new_config()));
+        assert!(dir.join("local/tokio").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merge_upstream_changes() {
+        let (dir, upstream, registry) = setup("normal-merge");
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        sync(&local, &registry);
+        let config_commit = find_head_commit(&local).unwrap().id();
+        let upstream_tip = commit_upstream(&upstream, "tokio", TOKIO_ENTRY);
+
+        sync(&local, &registry);
+
+        let head_commit = find_head_commit(&local).unwrap().id();
+        assert!(local.graph_descendant_of(head_commit, upstream_tip).unwrap());
+        assert!(local.graph_descendant_of(head_commit, config_commit).unwrap());
+        assert!(is_current_config(&registry.uri, &new_config()));
+        assert!(dir.join("local/serde").exists());
+        assert!(dir.join("local/tokio").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keep_up_to_date_index() {
+        let (dir, _upstream, registry) = setup("no-op");
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        sync(&local, &registry);
+        let head_commit = find_head_commit(&local).unwrap().id();
+
+        sync(&local, &registry);
+
+        assert_eq!(head_commit, find_head_commit(&local).unwrap().id());
+        assert!(is_current_config(&registry.uri, &new_config()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_file_url_upstream() {
+        let (dir, upstream, mut registry) = setup("file-url");
+        registry.upstream = Some(format!("file://{}", dir.join("upstream").to_str().unwrap()));
+        registry.git.token = Some(String::from("unused"));
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        let upstream_tip = commit_upstream(&upstream, "tokio", TOKIO_ENTRY);
+
+        sync(&local, &registry);
+
+        assert!(local.graph_descendant_of(find_head_commit(&local).unwrap().id(), upstream_tip).unwrap());
+        assert!(dir.join("local/tokio").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drop_reserved_upstream_crates() {
+        let (dir, upstream, mut registry) = setup("reserved");
+        registry.reserved = vec![String::from("acme-*")];
+        commit_upstream(&upstream, "ac/me/acme_squatted", ACME_SQUATTED);
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        sync(&local, &registry);
+        assert!(!dir.join("local/ac/me/acme_squatted").exists());
+
+        // A private crate is published to the local index
+        fs::create_dir_all(dir.join("local/ac/me")).unwrap();
+        fs::write(dir.join("local/ac/me/acme-tool"), ACME_PRIVATE).unwrap();
+        local.index().unwrap().add_path(Path::new("ac/me/acme-tool")).unwrap();
+        commit_index(&local, "Publish acme-tool").unwrap();
+        commit_upstream(&upstream, "ac/me/acme-tool", ACME_SQUATTED);
+        commit_upstream(&upstream, "to/ki/tokio", TOKIO_ENTRY);
+
+        sync(&local, &registry);
+
+        assert_eq!(ACME_PRIVATE, fs::read_to_string(dir.join("local/ac/me/acme-tool")).unwrap());
+        assert!(!dir.join("local/ac/me/acme_squatted").exists());
+        assert!(dir.join("local/to/ki/tokio").exists());
+        let head_tree = find_head_commit(&local).unwrap().tree().unwrap();
+        let private_id = head_tree.get_path(Path::new("ac/me/acme-tool")).unwrap().id();
+        assert_eq!(ACME_PRIVATE.as_bytes(), local.find_blob(private_id).unwrap().content());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_upstream_without_config() {
+        for &track_upstream in [false, true].iter() {
+            let dir = env::temp_dir().join(format!("cargo-mirage-no-config-{}-{}", track_upstream, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let upstream = Repository::init_bare(dir.join("upstream")).unwrap();
+            commit_upstream(&upstream, "serde", SERDE_ENTRY);
+            let mut registry = config::Configuration::default().crate_registry.remove(0);
+            registry.uri = String::from(dir.join("local").to_str().unwrap());
+            registry.upstream = Some(String::from(dir.join("upstream").to_str().unwrap()));
+            registry.track_upstream = track_upstream;
+            let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+            assert_eq!(None, read_config_from_file(&registry.uri));
+
+            sync(&local, &registry);
+            assert!(is_current_config(&registry.uri, &new_config()));
+            assert!(dir.join("local/serde").exists());
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn track_upstream_changes() {
+        let (dir, upstream, mut registry) = setup("track");
+        registry.track_upstream = true;
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        let upstream_tip = upstream.refname_to_id("refs/heads/master").unwrap();
+        sync(&local, &registry);
+        let head_commit = find_head_commit(&local).unwrap();
+        assert_eq!(vec![upstream_tip], head_commit.parent_ids().collect::<Vec<_>>());
+        assert!(is_current_config(&registry.uri, &new_config()));
+
+        sync(&local, &registry);
+        assert_eq!(head_commit.id(), find_head_commit(&local).unwrap().id());
+
+        let upstream_tip = commit_upstream(&upstream, "tokio", TOKIO_ENTRY);
+        sync(&local, &registry);
+        assert_eq!(vec![upstream_tip], find_head_commit(&local).unwrap().parent_ids().collect::<Vec<_>>());
+        assert!(is_current_config(&registry.uri, &new_config()));
+        assert!(dir.join("local/tokio").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn track_upstream_with_mirror_config() {
+        let (dir, upstream, mut registry) = setup("track-config");
+        registry.track_upstream = true;
+        let upstream_tip = commit_upstream(&upstream, "config.json", &serde_json::to_string(&new_config()).unwrap());
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        sync(&local, &registry);
+        let head_commit = find_head_commit(&local).unwrap();
+        assert_eq!(vec![upstream_tip], head_commit.parent_ids().collect::<Vec<_>>());
+
+        // Upstream already has the configuration of the mirror, the local history isn't reset again
+        sync(&local, &registry);
+        assert_eq!(head_commit.id(), find_head_commit(&local).unwrap().id());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rebuild_rewritten_upstream_history() {
+        let (dir, upstream, mut registry) = setup("rewritten");
+        registry.prune_rewritten_history = true;
+        let local = open_git_repo(&registry, &config::UpstreamHttp::default(), false).unwrap();
+        sync(&local, &registry);
+        let old_head = find_head_commit(&local).unwrap().id();
+        let squashed = squash_upstream(&upstream, "tokio", TOKIO_ENTRY);
+
+        fetch_upstream_master(&local, &registry).unwrap();
+        assert!(upstream_history_rewritten(&local).unwrap());
+        sync_with_upstream_master(&local, &registry, &new_config()).unwrap();
+
+        let head_commit = find_head_commit(&local).unwrap();
+        assert_eq!(vec![squashed], head_commit.parent_ids().collect::<Vec<_>>());
+        assert!(!upstream_history_rewritten(&local).unwrap());
+        assert!(is_current_config(&registry.uri, &new_config()));
+        assert!(dir.join("local/tokio").exists());
+        assert!(!dir.join("local/serde").exists());
+        assert!(Repository::open(&registry.uri).unwrap().find_commit(old_head).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
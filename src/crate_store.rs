@@ -1,86 +1,871 @@
-use actix_web::{server, App, middleware, Path, fs, http::Method};
+use actix::Arbiter;
+use actix_web::{server, App, middleware, Path, fs, pred, HttpRequest, HttpResponse, HttpMessage, Responder};
+use actix_web::http::{header, header::{EntityTag, HeaderValue}};
 use super::config;
+use super::config::{AdvisoryPolicy, BindAddress};
+use advisories::{self, AdvisoryDb};
+use auth;
+use denylist::{self, Denylist};
+use index_entry::{self, index_file_path, IndexEntry};
+use index_view::{self, IndexViewBuilder};
+use approval::{self, Approval, ApprovalList};
+use licenses::{self, LicenseList};
+use limits;
+use proxy;
+use quarantine::QuarantineList;
+use reloadable;
+use snapshots;
+use schedule::SyncTrigger;
+use supervisor::Supervisor;
+use throttle::Throttle;
+use std::sync::Arc;
+use std::cmp::Reverse;
 use std::io;
-use std::io::{BufRead, BufReader};
-use std::fs::{OpenOptions, create_dir_all, remove_file, File};
+use std::io::{BufRead, BufReader, Write};
+use std::fs::{self as std_fs, create_dir_all, read_dir, remove_file, File};
+use std::net;
+use std::os::unix::fs::FileTypeExt;
 use std::path;
 use reqwest;
-use futures_cpupool::CpuPool;
+use sha2::{Digest, Sha256};
+use hex;
+use git2::Oid;
+use futures::Future;
+use tokio_uds::UnixListener;
+use futures_cpupool::{CpuFuture, CpuPool};
 use std::sync::mpsc;
-use std::thread;
+use std::time::{Duration, SystemTime};
 use glob::glob;
-use serde_json;
-
-#[derive(Deserialize,Debug,Clone, PartialEq)]
-struct CrateMetadata {
-    pub name: String,
-    pub vers: String,
-    pub cksum: String,
-    pub yanked: bool,
-}
-
-pub fn start(config : &config::CrateStore, registry_uri: &str, crate_download_trigger: mpsc::Receiver<()>) {
-    let crate_store_connection_str = config::crate_store_connection_string(&config);
-    let folder_for_server = config.folder.clone();
-    server::new(move || {
-        let folder = folder_for_server.clone();
-        App::new()
-        .middleware(middleware::Logger::default())
-        .resource("/{name}/{version}/download",
-            |r| r.method(Method::GET).with(move |args| fetch_crate(&folder, args)))
-    })
-    .bind(&crate_store_connection_str)
-    .expect(&format!("Can not bind to {}", crate_store_connection_str))
-    .shutdown_timeout(0)    // <- Set shutdown timeout to 0 seconds (default 60s)
-    .workers(config.workers as usize)
-    .start();
-    println!("Starting crate store on {}", crate_store_connection_str);
 
+// Crate files never change once published, so they can be cached forever
+const IMMUTABLE_CACHE_CONTROL : &str = "public, max-age=31536000, immutable";
+
+/// Location & policies of a registry, shared by the server workers & the crawler of the registry
+struct ServedRegistry {
+    name: String,
+    folder: String,
+    uri: String,
+    quarantine: Option<QuarantineList>,
+    approvals: Option<ApprovalList>,
+    denylist: Denylist,
+    advisories: Option<Arc<AdvisoryDb>>,
+    licenses: Option<LicenseList>,
+    index_view: Option<String>,
+    offline: bool,
+}
+
+impl ServedRegistry {
+    fn new(config: &config::CrateStore, registry: &config::CrateRegistry, advisory_db: &Option<Arc<AdvisoryDb>>) -> ServedRegistry {
+        ServedRegistry {
+            name: registry.name.clone(),
+            folder: registry.folder(config),
+            uri: registry.uri.clone(),
+            quarantine: registry.quarantine.as_ref().map(|quarantine| QuarantineList::new(&registry.uri, quarantine)),
+            approvals: if registry.require_approval { Some(ApprovalList::new(&registry.uri)) } else { None },
+            denylist: Denylist::new(&registry.uri, &registry.denylist),
+            advisories: advisory_db.clone().filter(|advisory_db| advisory_db.applies_to(&registry.name)),
+            licenses: registry.licenses.as_ref().map(|licenses| LicenseList::new(&registry.uri, licenses)),
+            index_view: registry.partial_index.clone(),
+            offline: config.offline,
+        }
+    }
+
+    /// Reloads the policies which were modified since they were read, before a request or a crawl checks them
+    fn reload(&self) {
+        if let Some(ref quarantine) = self.quarantine {
+            quarantine.reload();
+        }
+        if let Some(ref approvals) = self.approvals {
+            approvals.reload();
+        }
+        self.denylist.reload();
+        if let Some(ref licenses) = self.licenses {
+            licenses.reload();
+        }
+    }
+
+    /// Whether a version affected by an advisory is handled according to `policy`
+    fn has_advisory(&self, name: &str, version: &str, policy: AdvisoryPolicy) -> bool {
+        self.advisories.as_ref()
+            .map(|advisories| advisories.policy == policy && advisories.is_affected(name, version))
+            .unwrap_or(false)
+    }
+
+    /// Whether a version is taken down, by the denylist or by an advisory
+    fn is_blocked(&self, name: &str, version: &str) -> bool {
+        self.denylist.is_denied(name, version) || self.has_advisory(name, version, AdvisoryPolicy::Block)
+    }
+
+    fn serves_crate(&self, name: &str) -> bool {
+        self.approvals.as_ref().map(|approvals| approvals.is_approved(name)).unwrap_or(true)
+    }
+
+    fn serves_version(&self, name: &str, version: &str, now: SystemTime) -> bool {
+        self.serves_crate(name) && !self.quarantine.as_ref()
+            .map(|quarantine| quarantine.is_quarantined(name, version, now))
+            .unwrap_or(false)
+            && !self.licenses.as_ref().map(|licenses| licenses.is_denied(name, version)).unwrap_or(false)
+    }
+
+    /// Whether a version is downloaded from upstream
+    fn mirrors_version(&self, registry: &config::CrateRegistry, name: &str, version: &str) -> bool {
+        !self.offline
+            && registry.filters.allows(name)
+            // Reserved crates are private, they are never downloaded from upstream
+            && !registry.is_reserved(name)
+            && self.serves_version(name, version, SystemTime::now())
+            && !self.is_blocked(name, version)
+    }
+
+    /// Records the license of a downloaded version from its manifest, unless it was checked before.
+    /// A version whose license can't be read is recorded as such, which denies it.
+    fn check_license(&self, name: &str, version: &str) {
+        let licenses = match self.licenses {
+            Some(ref licenses) if !licenses.is_checked(name, version) && crate_exists(&self.folder, name, version) => licenses,
+            _ => return,
+        };
+        let crate_file = format!("{folder}/{name}/{name}-{version}.crate", folder=self.folder, name=name, version=version);
+        let recorded = match licenses::read_crate_license(path::Path::new(&crate_file), name, version) {
+            Ok(license) => licenses.record(name, version, license.as_deref()).map(|()| {
+                if licenses.is_denied(name, version) {
+                    println!("License {} of {}-{} is denied", license.as_ref().map_or("(none)", String::as_str), name, version);
+                }
+            }),
+            Err(e) => {
+                eprintln!("Denying {}-{} as its license could not be read: {}", name, version, e);
+                licenses.record_unreadable(name, version, &e.to_string())
+            },
+        };
+        if let Err(e) = recorded {
+            eprintln!("Could not check the license of {}-{}: {:?}", name, version, e);
+        }
+    }
+
+    /// Whether index files are served as they are
+    fn serves_whole_index(&self) -> bool {
+        let advisories_flagged_only = self.advisories.as_ref()
+            .map(|advisories| advisories.policy == AdvisoryPolicy::Flag)
+            .unwrap_or(true);
+        self.quarantine.is_none() && self.approvals.is_none() && self.denylist.is_empty() && advisories_flagged_only
+            && self.licenses.is_none()
+    }
+
+    /// Removes the versions which aren't served from the lines of an index file,
+    /// and yanks the blocked versions & the ones affected by advisories under the yank policy
+    fn filter_index_lines(&self, lines: Vec<String>, now: SystemTime) -> Vec<String> {
+        lines.into_iter()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match IndexEntry::parse(&line) {
+                Ok(ref crate_entry) if !self.serves_version(&crate_entry.name, &crate_entry.vers, now) => None,
+                Ok(mut crate_entry) => {
+                    if !crate_entry.yanked && (self.is_blocked(&crate_entry.name, &crate_entry.vers)
+                        || self.has_advisory(&crate_entry.name, &crate_entry.vers, AdvisoryPolicy::Yank)) {
+                        crate_entry.yanked = true;
+                        Some(crate_entry.to_line())
+                    } else {
+                        Some(line)
+                    }
+                },
+                // The policies can't be checked on entries which can't be parsed
+                Err(e) => {
+                    eprintln!("Leaving out invalid index entry of {}: {}", self.name, e);
+                    None
+                },
+            })
+            .collect()
+    }
+
+    /// Removes the denied versions from the crate store. Crates are stored under the name of their index entries,
+    /// which may differ in case from the denylist.
+    fn remove_denied_crates(&self) {
+        let names = self.denylist.names();
+        if names.is_empty() {
+            return;
+        }
+        let crate_folders = match read_dir(&self.folder) {
+            Ok(crate_folders) => crate_folders,
+            Err(_) => return,
+        };
+        let denied_folders = crate_folders
+            .filter_map(|crate_folder| crate_folder.ok())
+            .filter_map(|crate_folder| crate_folder.file_name().to_str()
+                .map(str::to_lowercase)
+                .filter(|name| names.contains(name))
+                .map(|name| (name, crate_folder.path())));
+        for (name, crate_folder) in denied_folders {
+            let crate_files = match read_dir(&crate_folder) {
+                Ok(crate_files) => crate_files,
+                Err(_) => continue,
+            };
+            let prefix = format!("{}-", name);
+            crate_files
+            .filter_map(|crate_file| crate_file.ok().map(|crate_file| crate_file.path()))
+            .filter(|crate_file| crate_file.file_name()
+                .and_then(|file_name| file_name.to_str())
+                .and_then(|file_name| file_name.get(..prefix.len())
+                    .filter(|file_prefix| file_prefix.eq_ignore_ascii_case(&prefix))
+                    .and_then(|_| file_name[prefix.len()..].strip_suffix(".crate")))
+                .map(|version| self.denylist.is_denied(&name, version))
+                .unwrap_or(false))
+            .for_each(|crate_file| match remove_file(&crate_file) {
+                Ok(()) => println!("Removed denied crate {:?}", crate_file),
+                Err(e) => eprintln!("Could not remove denied crate {:?}: {:?}", crate_file, e),
+            });
+        }
+    }
+}
+
+pub fn start(
+    config : &config::CrateStore,
+    http: &config::UpstreamHttp,
+    registries: Vec<(config::CrateRegistry, mpsc::Receiver<()>, Arc<SyncTrigger>)>,
+    supervisor: &Arc<Supervisor>) {
+    // Offline, the advisory database is read as it is
+    let advisory_db = config.advisories.as_ref()
+        .map(|advisories| config::Advisories { local: advisories.local || config.offline, ..advisories.clone() })
+        .as_ref()
+        .map(|advisories| advisories::start(advisories, http, supervisor));
+    let path_prefix = config.path_prefix();
+    let served_registries = registries.iter()
+        .map(|(registry, _, _)| (format!("{}{}", path_prefix, registry.prefix()), Arc::new(ServedRegistry::new(config, registry, &advisory_db))))
+        .collect::<Vec<_>>();
+    // Longer prefixes come first, so a registry served at the root doesn't hide the others
+    let mut registries_for_server = served_registries.clone();
+    registries_for_server.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    let sync_triggers = registries.iter()
+        .map(|(registry, _, sync_trigger)| (registry.name.clone(), sync_trigger.clone()))
+        .collect::<Vec<_>>();
+    let offline = config.offline;
+    let supervisor_for_server = supervisor.clone();
+    let tokens = config.auth.as_ref().map(|auth| Arc::new(auth::TokenStore::new(&auth.token_file)));
+    let admins = config.auth.as_ref().map(|auth| auth.admins.clone()).unwrap_or_default();
+    let hook_tokens = config.auth.as_ref().map(|auth| [auth.admins.clone(), auth.hooks.clone()].concat()).unwrap_or_default();
+    let limits = config.limits.clone().unwrap_or(config::Limits{ downloads: None, index: None });
+    let download_limiter = limits.downloads.as_ref().map(|limit| Arc::new(limits::RateLimiter::new(limit)));
+    let index_limiter = limits.index.as_ref().map(|limit| Arc::new(limits::RateLimiter::new(limit)));
+    let app_factory = move || {
+        // The admin API is only available to the admin tokens. Its scopes are under `/-/`, which is never a crate name,
+        // and come first, as a registry may be served at the root.
+        let app = App::new().middleware(middleware::Logger::default());
+        let app = match tokens {
+            Some(ref tokens) => {
+                let hook_token_store = tokens.clone();
+                let tokens = tokens.clone();
+                let admins = admins.clone();
+                let hook_tokens = hook_tokens.clone();
+                let sync_triggers = sync_triggers.clone();
+                let registry_sync_triggers = sync_triggers.clone();
+                let pending_registries = registries_for_server.clone();
+                let decision_registries = registries_for_server.clone();
+                let denylist_registries = registries_for_server.clone();
+                let deny_registries = registries_for_server.clone();
+                let allow_registries = registries_for_server.clone();
+                let advisory_registries = registries_for_server.clone();
+                let license_registries = registries_for_server.clone();
+                let supervisor = supervisor_for_server.clone();
+                let app = app.scope(&format!("{}/-/admin", path_prefix), move |scope| {
+                    scope
+                    .middleware(auth::TokenAuth::admins(tokens, admins))
+                    .resource("/registries/{registry}/crates/pending", move |r| {
+                        r.get().with(move |registry: Path<String>| list_pending_crates(&pending_registries, &registry))
+                    })
+                    .resource("/registries/{registry}/crates/{name}/{decision}", move |r| {
+                        r.post().with(move |args| decide_crate(&decision_registries, args))
+                    })
+                    .resource("/registries/{registry}/denylist", move |r| {
+                        r.get().with(move |registry: Path<String>| list_denylist(&denylist_registries, &registry));
+                        r.post().with(move |(registry, entry): (Path<String>, String)| deny_versions(&deny_registries, &registry, &entry));
+                        r.delete().with(move |(registry, entry): (Path<String>, String)| allow_versions(&allow_registries, &registry, &entry));
+                    })
+                    .resource("/registries/{registry}/licenses/denied", move |r| {
+                        r.get().with(move |registry: Path<String>| list_denied_licenses(&license_registries, &registry))
+                    })
+                    .resource("/advisories", move |r| {
+                        r.get().f(move |_| list_advisories(&advisory_registries))
+                    })
+                    .resource("/tasks", move |r| {
+                        r.get().f(move |_| HttpResponse::Ok().json(supervisor.tasks()))
+                    })
+                });
+                // Syncs with upstream can't be triggered offline
+                if offline {
+                    app
+                } else {
+                    app.scope(&format!("{}/-/hooks", path_prefix), move |scope| {
+                        scope
+                        .middleware(auth::TokenAuth::admins(hook_token_store, hook_tokens))
+                        .resource("/sync", move |r| {
+                            r.post().f(move |_| trigger_syncs(&sync_triggers, None))
+                        })
+                        .resource("/sync/{registry}", move |r| {
+                            r.post().with(move |registry: Path<String>| trigger_syncs(&registry_sync_triggers, Some(&registry)))
+                        })
+                    })
+                }
+            },
+            None => app,
+        };
+        registries_for_server.iter()
+        .fold(app, |app, (prefix, registry)| {
+            let crate_registry = registry.clone();
+            let index_registry = registry.clone();
+            let snapshot_registry = registry.clone();
+            let tokens = tokens.clone();
+            let download_limiter = download_limiter.clone();
+            let index_limiter = index_limiter.clone();
+            let snapshot_limiter = index_limiter.clone();
+            app.scope(prefix, move |scope| {
+                let scope = match tokens {
+                    Some(tokens) => scope.middleware(auth::TokenAuth::new(tokens)),
+                    None => scope,
+                };
+                scope
+                .resource("/{name}/{version}/download", move |r| {
+                    if let Some(limiter) = download_limiter {
+                        r.middleware(limits::RateLimit::new(limiter));
+                    }
+                    r.route().filter(pred::Any(pred::Get()).or(pred::Head()))
+                        .with(move |(req, args)| fetch_crate(&crate_registry, req, args))
+                })
+                .resource("/index/{file:.*}", move |r| {
+                    if let Some(limiter) = index_limiter {
+                        r.middleware(limits::RateLimit::new(limiter));
+                    }
+                    r.route().filter(pred::Any(pred::Get()).or(pred::Head()))
+                        .with(move |(req, args)| fetch_index_file(&index_registry, req, args))
+                })
+                .resource("/snapshots/{id}/{file:.*}", move |r| {
+                    if let Some(limiter) = snapshot_limiter {
+                        r.middleware(limits::RateLimit::new(limiter));
+                    }
+                    r.route().filter(pred::Any(pred::Get()).or(pred::Head()))
+                        .with(move |(req, args)| fetch_snapshot_file(&snapshot_registry, req, args))
+                })
+            })
+        })
+    };
+    let bind_addresses = config.bind_addresses().unwrap_or_else(|e| panic!("Invalid bind address: {}", e));
+    let mut tcp_listeners = Vec::new();
+    let mut listening = Vec::new();
+    for address in bind_addresses {
+        let bound = match address {
+            BindAddress::Tcp(socket_addr) => net::TcpListener::bind(socket_addr)
+                .map(|listener| tcp_listeners.push(listener)),
+            // Unix domain sockets are served by a single thread, e.g. behind a reverse proxy on the same host
+            BindAddress::Unix(ref socket_path) => bind_unix_socket(socket_path)
+                .map(|listener| {
+                    server::new(app_factory.clone())
+                        .shutdown_timeout(config.shutdown_timeout())
+                        .start_incoming(listener.incoming(), false);
+                }),
+        };
+        match bound {
+            Ok(()) => listening.push(address.to_string()),
+            Err(e) => eprintln!("Can not bind to {}: {:?}", address, e),
+        }
+    }
+    if listening.is_empty() {
+        panic!("Could not bind the crate store to any address");
+    }
+    if !tcp_listeners.is_empty() {
+        tcp_listeners.into_iter()
+            .fold(server::new(app_factory), |tcp_server, listener| tcp_server.listen(listener))
+            .shutdown_timeout(config.shutdown_timeout())
+            .workers(config.workers as usize)
+            .start();
+    }
+    println!("Starting crate store on {}", listening.join(", "));
+
+    let throttle = Arc::new(Throttle::new(&config.crawling));
+    let client = crawling_client(config, http);
+    for ((registry, crate_download_trigger, _), (_, served_registry)) in registries.into_iter().zip(served_registries) {
+        start_crawler(config, registry, served_registry, crate_download_trigger, client.clone(), throttle.clone(), supervisor);
+    }
+}
+
+/// Binds a Unix domain socket, replacing the socket file left over by a previous run
+fn bind_unix_socket(socket_path: &path::Path) -> io::Result<UnixListener> {
+    if std_fs::symlink_metadata(socket_path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+        remove_file(socket_path)?;
+    }
+    UnixListener::bind(socket_path, Arbiter::handle())
+}
+
+/// HTTP client of the crawlers. reqwest 0.8 has a single timeout, for connecting & getting the response, then per read.
+fn crawling_client(config: &config::CrateStore, http: &config::UpstreamHttp) -> reqwest::Client {
+    let mut headers = reqwest::header::Headers::new();
+    headers.set(reqwest::header::UserAgent::new(config.crawling.user_agent.clone()));
+    let mut builder = reqwest::Client::builder();
+    builder.default_headers(headers)
+        .timeout(http.connect_timeout() + http.read_timeout());
+    for ca_certificate in &http.ca_certificates {
+        let pem = std_fs::read(ca_certificate)
+            .unwrap_or_else(|e| panic!("Could not read CA certificate {}: {:?}", ca_certificate, e));
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .unwrap_or_else(|e| panic!("Invalid CA certificate {}: {:?}", ca_certificate, e));
+        builder.add_root_certificate(certificate);
+    }
+    if let Some(proxy) = proxy::client_proxy(http) {
+        builder.proxy(proxy);
+    }
+    builder.build().expect("Could not create HTTP client for crawling")
+}
+
+fn start_crawler(
+    config: &config::CrateStore,
+    registry: config::CrateRegistry,
+    served_registry: Arc<ServedRegistry>,
+    crate_download_trigger: mpsc::Receiver<()>,
+    client: reqwest::Client,
+    throttle: Arc<Throttle>,
+    supervisor: &Arc<Supervisor>) {
     let threadpool = CpuPool::new(config.crawlers as usize);
-    let registry_uri = String::from(registry_uri);
-    let folder_for_threadpool = config.folder.clone();
-    thread::spawn(move || {
+    let task_supervisor = supervisor.clone();
+    supervisor.supervise(&format!("crawler {}", registry.name), move || {
         loop {
-            // Block while waiting for trigger
-            crate_download_trigger.recv()
-            .map_err(|_| eprintln!("Fail to get trigger to download crates"))
-            .expect("Could not wait on download trigger");
-
-            println!("Starting fetching crates");            
-            let matches = glob(&format!("{}/**/*", registry_uri)).expect("Could not match crate glob pattern");
-            matches
-            .filter_map(|glob_result| {
-                match glob_result {
-                    Ok(path) => {
-                        if path.is_file() {
-                            Some(path)
-                        } else {
-                            None
-                        }
-                    },
-                    Err(_) => None
+            // Wait for the trigger, the registry task stops triggering once shutting down
+            match crate_download_trigger.recv_timeout(Duration::from_secs(1)) {
+                Ok(()) => (),
+                Err(mpsc::RecvTimeoutError::Timeout) if !task_supervisor.is_shutting_down() => continue,
+                Err(_) => break,
+            }
+
+            println!("Starting fetching crates of {}", registry.name);
+            served_registry.reload();
+            served_registry.remove_denied_crates();
+            let mut names = Vec::new();
+            index_files(&registry.uri)
+            .take_while(|_| !task_supervisor.is_shutting_down())
+            .filter_map(|path| index_entry::read_index_file(&path)
+                .map_err(|e| eprintln!("Could not read index file {:?}: {:?}", path, e))
+                .ok())
+            .for_each(|crate_list| {
+                if let Some(crate_entry) = crate_list.first().filter(|crate_entry| registry.filters.allows(&crate_entry.name)) {
+                    names.push(crate_entry.name.clone());
                 }
-            })
-            .map(|path| File::open(path).unwrap())
-            .map(crates_as_json)
-            .for_each(|crate_list| 
                 crate_list
                 .into_iter()
+                .filter(|crate_entry| served_registry.mirrors_version(&registry, &crate_entry.name, &crate_entry.vers))
                 .for_each(|crate_entry| {
-                    let folder = folder_for_threadpool.clone();
-                    threadpool.spawn_fn(move || {
-                        download_crate(folder, crate_entry.name, crate_entry.vers, crate_entry.cksum.into_bytes())
-                    }).forget();
-                }));
+                    spawn_download(&threadpool, &client, &throttle, &registry, &served_registry, &task_supervisor, crate_entry).forget();
+                })
+            });
+
+            // Crates which were never mirrored before await approval, they are fetched on a later crawl once approved
+            if let Some(ref approvals) = served_registry.approvals {
+                let folder = &served_registry.folder;
+                approvals.queue_new(names, |name| path::Path::new(folder).join(name).exists())
+                    .unwrap_or_else(|e| eprintln!("Could not queue new crates of {}: {:?}", registry.name, e));
+            }
+            update_index_view(&registry, &served_registry, |crate_entry| served_registry.mirrors_version(&registry, &crate_entry.name, &crate_entry.vers))
+                .unwrap_or_else(|e| eprintln!("Could not update the index view of {}: {:?}", registry.name, e));
         }
     });
 }
 
-fn fetch_crate(folder: &str, params: Path<(String, String)>) -> io::Result<fs::NamedFile> {
+/// Index files of the registry repository
+fn index_files(registry_uri: &str) -> impl Iterator<Item = path::PathBuf> {
+    let registry_uri = String::from(registry_uri);
+    glob(&format!("{}/**/*", registry_uri)).expect("Could not match crate glob pattern")
+        .filter_map(Result::ok)
+        .filter(move |path| path.is_file() && is_index_file(&registry_uri, path))
+}
+
+/// Regenerates the derived index of a partial mirror, with the versions which are in the crate store
+/// or scheduled for download, so that cargo never resolves to versions the mirror doesn't have
+fn update_index_view<F>(registry: &config::CrateRegistry, served_registry: &ServedRegistry, scheduled: F) -> io::Result<()>
+    where F: Fn(&IndexEntry) -> bool {
+    let view_uri = match registry.partial_index {
+        Some(ref view_uri) => view_uri,
+        None => return Ok(()),
+    };
+    let mut view = IndexViewBuilder::new(view_uri).map_err(io::Error::other)?;
+    let config_json = std_fs::read(path::Path::new(&registry.uri).join("config.json"))?;
+    view.add_file("config.json", &config_json).map_err(io::Error::other)?;
+    let mut versions = 0;
+    for path in index_files(&registry.uri) {
+        let crate_entries = match index_entry::read_index_file(&path) {
+            Ok(crate_entries) => crate_entries,
+            Err(e) => {
+                eprintln!("Could not read index file {:?}: {:?}", path, e);
+                continue;
+            },
+        };
+        let contents = crate_entries.into_iter()
+            .filter(|crate_entry| crate_exists(&served_registry.folder, &crate_entry.name, &crate_entry.vers) || scheduled(crate_entry))
+            .map(|crate_entry| {
+                versions += 1;
+                format!("{}\n", crate_entry.to_line())
+            })
+            .collect::<String>();
+        if contents.is_empty() {
+            continue;
+        }
+        let file = path.strip_prefix(&registry.uri).ok()
+            .and_then(|relative| relative.to_str())
+            .map(|relative| relative.replace(path::MAIN_SEPARATOR, "/"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid index file {:?}", path)))?;
+        view.add_file(&file, contents.as_bytes()).map_err(io::Error::other)?;
+    }
+    if let Some(commit) = view.commit().map_err(io::Error::other)? {
+        println!("Updated the index view of {} with {} versions in {}", registry.name, versions, commit);
+    }
+    Ok(())
+}
+
+fn spawn_download(
+    threadpool: &CpuPool,
+    client: &reqwest::Client,
+    throttle: &Arc<Throttle>,
+    registry: &config::CrateRegistry,
+    served_registry: &Arc<ServedRegistry>,
+    supervisor: &Arc<Supervisor>,
+    crate_entry: IndexEntry) -> CpuFuture<(), io::Error> {
+    let in_flight = supervisor.track();
+    let supervisor = supervisor.clone();
+    let folder = served_registry.folder.clone();
+    let download_uri = crate_download_uri(registry.download(), &crate_entry.name, &crate_entry.vers);
+    let client = client.clone();
+    let throttle = throttle.clone();
+    let served_registry = served_registry.clone();
+    threadpool.spawn_fn(move || {
+        let _in_flight = in_flight;
+        // Downloads which didn't start yet are dropped on shutdown, or while upstream is unavailable
+        // until the backoff since the last failure elapsed. The next crawl schedules them again.
+        let task = format!("crawler {}", served_registry.name);
+        if supervisor.is_shutting_down() || supervisor.is_backing_off(&task) {
+            return Ok(());
+        }
+        let (name, vers) = (crate_entry.name.clone(), crate_entry.vers.clone());
+        match download_crate(&client, &throttle, folder, download_uri, crate_entry.name, crate_entry.vers, crate_entry.cksum) {
+            Ok(true) => supervisor.report_success(&task),
+            Ok(false) => (),
+            // Missing crates are the upstream's problem, other errors mean it is unavailable
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                supervisor.report_failure(&task, format!("Could not download {}-{}: {}", name, vers, e));
+                return Err(e);
+            },
+        }
+        served_registry.check_license(&name, &vers);
+        Ok(())
+    })
+}
+
+/// Downloads the given versions once, e.g. the dependency closure of `cargo-mirage sync`,
+/// following the same policies as the crawler
+pub fn download_versions(config: &config::CrateStore, http: &config::UpstreamHttp, registry: &config::CrateRegistry, crate_entries: Vec<IndexEntry>) {
+    let advisory_db = config.advisories.as_ref().map(advisories::load);
+    let served_registry = Arc::new(ServedRegistry::new(config, registry, &advisory_db));
+    served_registry.reload();
+    let threadpool = CpuPool::new(config.crawlers as usize);
+    let client = crawling_client(config, http);
+    let throttle = Arc::new(Throttle::new(&config.crawling));
+    let supervisor = Arc::new(Supervisor::new());
+    let downloads = crate_entries.into_iter()
+        .filter(|crate_entry| served_registry.mirrors_version(registry, &crate_entry.name, &crate_entry.vers))
+        .map(|crate_entry| spawn_download(&threadpool, &client, &throttle, registry, &served_registry, &supervisor, crate_entry))
+        .collect::<Vec<_>>();
+    println!("Downloading {} versions of {}", downloads.len(), registry.name);
+    for download in downloads {
+        download.wait().unwrap_or_else(|e| eprintln!("Could not download crate: {:?}", e));
+    }
+    update_index_view(registry, &served_registry, |_| false)
+        .unwrap_or_else(|e| eprintln!("Could not update the index view of {}: {:?}", registry.name, e));
+}
+
+fn find_served_registry<'a>(registries: &'a [(String, Arc<ServedRegistry>)], name: &str) -> io::Result<&'a ServedRegistry> {
+    registries.iter()
+        .map(|(_, registry)| registry.as_ref())
+        .find(|registry| registry.name == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No registry {}", name)))
+}
+
+fn find_approving_registry<'a>(registries: &'a [(String, Arc<ServedRegistry>)], name: &str) -> io::Result<&'a ServedRegistry> {
+    find_served_registry(registries, name)
+        .and_then(|registry| if registry.approvals.is_some() {
+            Ok(registry)
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound, format!("Registry {} doesn't require approval", name)))
+        })
+}
+
+fn list_pending_crates(registries: &[(String, Arc<ServedRegistry>)], name: &str) -> io::Result<HttpResponse> {
+    let registry = find_approving_registry(registries, name)?;
+    let pending = approval::pending(&registry.uri)?;
+    Ok(HttpResponse::Ok().json(pending))
+}
+
+fn decide_crate(registries: &[(String, Arc<ServedRegistry>)], params: Path<(String, String, String)>) -> io::Result<HttpResponse> {
+    let (registry_name, crate_name, decision) = params.into_inner();
+    let registry = find_approving_registry(registries, &registry_name)?;
+    let approval = match decision.as_str() {
+        "approve" => Approval::Approved,
+        "reject" => Approval::Rejected,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    match approval::decide(&registry.uri, &[crate_name], approval) {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+fn list_denylist(registries: &[(String, Arc<ServedRegistry>)], name: &str) -> io::Result<HttpResponse> {
+    let registry = find_served_registry(registries, name)?;
+    registry.reload();
+    Ok(HttpResponse::Ok().json(registry.denylist.entries()))
+}
+
+/// Takes down the versions of a `name@version`, `name@semver-req` or `name` entry, starting right away
+fn deny_versions(registries: &[(String, Arc<ServedRegistry>)], name: &str, entry: &str) -> io::Result<HttpResponse> {
+    let registry = find_served_registry(registries, name)?;
+    match denylist::add_entry(&registry.uri, entry) {
+        Ok(_) => {
+            registry.reload();
+            registry.remove_denied_crates();
+            Ok(HttpResponse::NoContent().finish())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Ok(HttpResponse::BadRequest().body(e.to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes an entry of the denylist managed through the admin API, the versions are downloaded again on the next crawl
+fn allow_versions(registries: &[(String, Arc<ServedRegistry>)], name: &str, entry: &str) -> io::Result<HttpResponse> {
+    let registry = find_served_registry(registries, name)?;
+    if denylist::remove_entry(&registry.uri, entry)? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+#[derive(Serialize)]
+struct DeniedLicense {
+    name: String,
+    version: String,
+    license: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>, // Why the license couldn't be read
+}
+
+/// Downloaded versions whose license is denied, for legal review
+fn list_denied_licenses(registries: &[(String, Arc<ServedRegistry>)], name: &str) -> io::Result<HttpResponse> {
+    let registry = find_served_registry(registries, name)?;
+    let licenses = registry.licenses.as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Registry {} has no license policy", name)))?;
+    licenses.reload();
+    let denied = licenses.denied().into_iter()
+        .map(|(name, version, license)| match license {
+            Ok(license) => DeniedLicense { name, version, license, error: None },
+            Err(error) => DeniedLicense { name, version, license: None, error: Some(error) },
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(denied))
+}
+
+#[derive(Serialize)]
+struct AffectedCrate {
+    registry: String,
+    advisory: String,
+    name: String,
+    versions: Vec<String>,
+    policy: AdvisoryPolicy,
+}
+
+/// Versions of the mirrored crates which are affected by an advisory, per registry
+fn list_advisories(registries: &[(String, Arc<ServedRegistry>)]) -> io::Result<HttpResponse> {
+    let affected = registries.iter()
+        .flat_map(|(_, registry)| registry.advisories.iter()
+            .flat_map(|advisory_db| advisory_db.advisories().into_iter().map(move |advisory| (advisory_db.policy, advisory)))
+            .filter_map(move |(policy, advisory)| {
+                let index_file = path::Path::new(&registry.uri).join(index_file_path(&advisory.package)?);
+                let versions = index_entry::read_index_file(&index_file).ok()?.into_iter()
+                    .filter(|crate_entry| advisory.affects(&crate_entry.name, &crate_entry.vers))
+                    .map(|crate_entry| crate_entry.vers)
+                    .collect::<Vec<_>>();
+                if versions.is_empty() {
+                    return None;
+                }
+                Some(AffectedCrate { registry: registry.name.clone(), advisory: advisory.id, name: advisory.package, versions, policy })
+            }))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(affected))
+}
+
+#[derive(Serialize)]
+struct SyncRequest {
+    registry: String,
+    coalesced: bool, // Whether a sync was pending already
+}
+
+/// Requests an immediate sync of the registries, or of a single one
+fn trigger_syncs(sync_triggers: &[(String, Arc<SyncTrigger>)], registry: Option<&str>) -> io::Result<HttpResponse> {
+    let requests = sync_triggers.iter()
+        .filter(|(name, _)| registry.map(|registry| registry == name).unwrap_or(true))
+        .map(|(name, sync_trigger)| SyncRequest { registry: name.clone(), coalesced: !sync_trigger.request() })
+        .collect::<Vec<_>>();
+    if requests.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No registry {}", registry.unwrap_or_default())));
+    }
+    Ok(HttpResponse::Accepted().json(requests))
+}
+
+fn fetch_crate(registry: &ServedRegistry, req: HttpRequest, params: Path<(String, String)>) -> io::Result<HttpResponse> {
     let (crate_name, crate_sem_version) = params.into_inner();
+    if index_file_path(&crate_name).is_none() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not a crate name", crate_name)));
+    }
+    registry.reload();
+    if registry.is_blocked(&crate_name, &crate_sem_version) {
+        return Ok(HttpResponse::Gone().finish());
+    }
+    if !registry.serves_version(&crate_name, &crate_sem_version, SystemTime::now()) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} {} is not served", crate_name, crate_sem_version)));
+    }
     // response
-    let crate_uri = format!("{folder}/{name}/{name}-{version}.crate", folder=folder, name=&crate_name, version=&crate_sem_version);
-    fs::NamedFile::open(crate_uri)
+    let crate_uri = format!("{folder}/{name}/{name}-{version}.crate", folder=registry.folder, name=&crate_name, version=&crate_sem_version);
+    let file = fs::NamedFile::open(crate_uri)?;
+    // Crates are always served with their checksum as ETag, those missing from the index aren't served
+    let checksum = find_checksum(&registry.uri, &crate_name, &crate_sem_version)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} {} is not in the index", crate_name, crate_sem_version)))?;
+    respond_with_checksum(req, file, &EntityTag::strong(checksum))
+}
+
+/// Serves a crate file using its checksum as ETag. The conditional headers are evaluated against the checksum,
+/// before the file is served, as the ETag derived from the file metadata would differ between mirrors.
+fn respond_with_checksum(mut req: HttpRequest, file: fs::NamedFile, etag: &EntityTag) -> io::Result<HttpResponse> {
+    let if_none_match = req.get_header::<header::IfNoneMatch>();
+    let if_match = req.get_header::<header::IfMatch>();
+    let not_modified = match if_none_match {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(ref items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    };
+    let precondition_failed = match if_match {
+        Some(header::IfMatch::Items(ref items)) => !items.iter().any(|item| item.strong_eq(etag)),
+        _ => false,
+    };
+    let range_outdated = match req.get_header::<header::IfRange>() {
+        Some(header::IfRange::EntityTag(ref tag)) => !tag.strong_eq(etag),
+        _ => false,
+    };
+
+    {
+        let headers = req.headers_mut();
+        if if_none_match.is_some() {
+            headers.remove(header::IF_NONE_MATCH);
+            headers.remove(header::IF_MODIFIED_SINCE);
+        }
+        if if_match.is_some() {
+            headers.remove(header::IF_MATCH);
+            headers.remove(header::IF_UNMODIFIED_SINCE);
+        }
+        if range_outdated {
+            headers.remove(header::RANGE);
+        }
+        headers.remove(header::IF_RANGE);
+    }
+
+    let mut response = if precondition_failed {
+        HttpResponse::PreconditionFailed().finish()
+    } else if not_modified {
+        HttpResponse::NotModified().finish()
+    } else {
+        file.respond_to(&req)?
+    };
+    let etag_value = HeaderValue::from_str(&etag.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    response.headers_mut().insert(header::ETAG, etag_value);
+    response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL));
+    Ok(response)
+}
+
+/// Whether a file of the registry repository is an index file, rather than its configuration or git metadata
+fn is_index_file(registry_uri: &str, file: &path::Path) -> bool {
+    file.strip_prefix(registry_uri)
+        .map(|relative| relative != path::Path::new("config.json") && !relative.components()
+            .any(|component| component.as_os_str().to_str().map(|name| name.starts_with('.')).unwrap_or(true)))
+        .unwrap_or(false)
+}
+
+fn find_checksum(registry_uri: &str, name: &str, version: &str) -> Option<String> {
+    index_entry::read_index_file(&path::Path::new(registry_uri).join(index_file_path(name)?)).ok()
+        .and_then(|crate_entries| crate_entries.into_iter().find(|crate_entry| crate_entry.vers == version))
+        .map(|crate_entry| crate_entry.cksum)
+}
+
+/// Responds with the served lines of an index file, or 404 when none are left
+fn respond_with_index_lines(registry: &ServedRegistry, lines: Vec<String>, file: &str) -> io::Result<HttpResponse> {
+    let lines = registry.filter_index_lines(lines, SystemTime::now());
+    if lines.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No served versions: {}", file)));
+    }
+    Ok(HttpResponse::Ok().body(lines.iter().map(|line| format!("{}\n", line)).collect::<String>()))
+}
+
+/// Serves the index as a sparse registry, straight from the working directory of the registry repository,
+/// or from the derived index of a partial mirror. Until the crawler commits the derived index, the whole index is served.
+fn fetch_index_file(registry: &ServedRegistry, req: HttpRequest, params: Path<String>) -> io::Result<HttpResponse> {
+    let file = params.into_inner();
+    let hidden = file.split('/').any(|segment| segment.is_empty() || segment.starts_with('.'));
+    if hidden {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Not an index file: {}", file)));
+    }
+    registry.reload();
+    if let Some(view_uri) = registry.index_view.as_ref().filter(|view_uri| index_view::is_committed(view_uri)) {
+        let (blob_id, contents) = index_view::read_view_file(view_uri, &file)
+            .map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Not in the index view: {}", file)))?;
+        if !registry.serves_whole_index() && file != "config.json" {
+            let lines = String::from_utf8_lossy(&contents).lines().map(String::from).collect();
+            return respond_with_index_lines(registry, lines, &file);
+        }
+        return Ok(respond_with_blob(&req, blob_id, contents, None));
+    }
+    let file_path = path::Path::new(&registry.uri).join(&file);
+    if registry.serves_whole_index() {
+        fs::NamedFile::open(file_path)?.respond_to(&req)
+    } else {
+        let lines = BufReader::new(File::open(file_path)?).lines().collect::<io::Result<Vec<_>>>()?;
+        respond_with_index_lines(registry, lines, &file)
+    }
+}
+
+/// Serves an index file as it was in a snapshot. Snapshots never change, so neither do their files,
+/// unless versions of the snapshot are still in quarantine or awaiting approval.
+fn fetch_snapshot_file(registry: &ServedRegistry, req: HttpRequest, params: Path<(String, String)>) -> io::Result<HttpResponse> {
+    let (id, file) = params.into_inner();
+    let (blob_id, contents) = snapshots::read_snapshot_file(&registry.uri, &id, &file)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Not in snapshot {}: {}", id, file)))?;
+    registry.reload();
+    if !registry.serves_whole_index() {
+        let lines = String::from_utf8_lossy(&contents).lines().map(String::from).collect();
+        return respond_with_index_lines(registry, lines, &file);
+    }
+    Ok(respond_with_blob(&req, blob_id, contents, Some(IMMUTABLE_CACHE_CONTROL)))
+}
+
+/// Responds with a file of the git history, identified by the id of its blob
+fn respond_with_blob(req: &HttpRequest, blob_id: Oid, contents: Vec<u8>, cache_control: Option<&'static str>) -> HttpResponse {
+    let etag = EntityTag::strong(blob_id.to_string());
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(ref items)) => items.iter().any(|item| item.weak_eq(&etag)),
+        None => false,
+    };
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.header(header::ETAG, etag.to_string());
+    if let Some(cache_control) = cache_control {
+        response.header(header::CACHE_CONTROL, cache_control);
+    }
+    if not_modified { response.finish() } else { response.body(contents) }
 }
 
 fn crate_exists(folder: &str, name: &str, version: &str) -> bool {
@@ -89,25 +874,50 @@ fn crate_exists(folder: &str, name: &str, version: &str) -> bool {
         .exists()
 }
 
-fn download_crate(folder: String, name: String, version: String, _checksum: Vec<u8>) -> Result<(), io::Error> {
+/// Builds the upstream download location of a crate, following the `dl` conventions of Cargo's config.json
+fn crate_download_uri(download: &str, name: &str, version: &str) -> String {
+    if download.contains("{crate}") || download.contains("{version}") {
+        download.replace("{crate}", name).replace("{version}", version)
+    } else {
+        format!("{download}/{name}/{version}/download", download=download.trim_end_matches('/'), name=name, version=version)
+    }
+}
+
+/// Downloads a crate unless it is in the store already. Returns whether it was downloaded.
+fn download_crate(
+    client: &reqwest::Client,
+    throttle: &Throttle,
+    folder: String,
+    download_uri: String,
+    name: String,
+    version: String,
+    checksum: String) -> Result<bool, io::Error> {
     if crate_exists(&folder, &name, &version) {
-        return Ok(())
+        return Ok(false)
     }
 
-    let name = String::from(name);
-    let version = String::from(version);
+    // The crate is downloaded next to its final location & only moved there once its checksum matches the index,
+    // so that neither a failed nor an interrupted download is ever served
     let file_uri = format!("{folder}/{name}/{name}-{version}.crate", folder=folder, name=&name, version=&version);
-    let file_uri_copy = file_uri.clone();
-    let path = path::Path::new(file_uri_copy.as_str());
-    let mut file = create_dir_all(path.parent().unwrap())
-        .and_then(|()| OpenOptions::new().write(true).create(true).open(path))
-        .expect(&format!("Could not open file {}", file_uri));
-
-    reqwest::get(format!("https://crates.io/api/v1/crates/{name}/{version}/download", name=name, version=version).as_str())
-    .and_then(move |mut x| x.copy_to(&mut file).map(|_| println!("Downloaded crate {}-{}", name, version)))
-    .or_else(|e| {
-        eprintln!("Removing file: {:?}", e);
-        remove_file(file_uri).map(|_| ())
+    let path = path::Path::new(file_uri.as_str());
+    create_dir_all(path.parent().unwrap())?;
+    throttle.wait_for_request();
+    reloadable::write_atomically(path, |file| {
+        let mut response = client.get(download_uri.as_str()).send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| if e.is_client_error() { io::Error::new(io::ErrorKind::NotFound, e) } else { io::Error::other(e) })?;
+        let mut writer = ChecksumWriter { file, hasher: Sha256::default() };
+        throttle.copy(&mut response, &mut writer)?;
+        let downloaded = hex::encode(writer.hasher.result());
+        if downloaded != checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("Checksum {} of {}-{} doesn't match the index", downloaded, name, version)));
+        }
+        Ok(())
+    })
+    .map(|()| {
+        println!("Downloaded crate {}-{}", name, version);
+        true
     })
 
     // TODO: Use Actix framework for the request. Interpret the "Location" header in the original request and forward
@@ -143,39 +953,290 @@ fn download_crate(folder: String, name: String, version: String, _checksum: Vec<
     });*/
 }
 
-fn crates_as_json(f: File) -> Vec<CrateMetadata> {
-    BufReader::new(f)
-    .lines()
-    .filter_map(|line| line.ok())
-    .map(|line| serde_json::from_str(line.as_str()))
-    .filter_map(|x : Result<CrateMetadata,_>| x.ok())
-    .collect()
+/// Writes a download to its file, computing its SHA-256 checksum on the way
+struct ChecksumWriter<'a> {
+    file: &'a mut File,
+    hasher: Sha256,
+}
+
+impl<'a> Write for ChecksumWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use actix_web::{Body, FromRequest};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use quarantine;
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    const SERDE_1 : &str = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aabb","features":{},"yanked":false}"#;
+
+    fn served_registry(dir: &path::Path, registry: config::CrateRegistry) -> ServedRegistry {
+        let mut crate_store = config::Configuration::default().crate_store;
+        crate_store.folder = dir.join("crates").to_str().unwrap().to_string();
+        let registry = config::CrateRegistry { uri: dir.join("index").to_str().unwrap().to_string(), folder: Some(String::new()), ..registry };
+        std_fs::create_dir_all(path::Path::new(&registry.uri).join(".git")).unwrap();
+        ServedRegistry::new(&crate_store, &registry, &None)
+    }
+
+    fn write_file(file: &path::Path, contents: &str) {
+        std_fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std_fs::write(file, contents).unwrap();
+    }
 
     #[test]
-    fn parse_crate_into_json() {
-        let crates_string = r#"{"name":"test_crate","vers":"0.0.1","deps":[],"cksum":"aabb","features":{},"yanked":false}"#;
-        let res : Result<CrateMetadata, _> = serde_json::from_str(crates_string);
-        let metadata = res.unwrap();
-        assert_eq!(
-            CrateMetadata{ name: String::from("test_crate"), vers: String::from("0.0.1"), cksum: String::from("aabb"), yanked: false},
-            metadata);
+    fn serve_crates_with_checksum_etag() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-fetch-crate-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let registry = served_registry(&dir, config::Configuration::default().crate_registry.remove(0));
+        write_file(&path::Path::new(&registry.uri).join(index_file_path("serde").unwrap()), &format!("{}\n", SERDE_1));
+        write_file(&dir.join("crates/serde/serde-1.0.0.crate"), "crate contents");
+        write_file(&dir.join("crates/serde/serde-2.0.0.crate"), "not in the index");
+        let fetch = |version: &'static str, request: TestRequest<()>| {
+            let req = request.param("name", "serde").param("version", version).finish();
+            let params = Path::<(String, String)>::extract(&req).unwrap();
+            fetch_crate(&registry, req, params)
+        };
+        let header = |response: &HttpResponse, name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from);
+
+        let response = fetch("1.0.0", TestRequest::default()).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some(String::from("\"aabb\"")), header(&response, header::ETAG));
+        assert_eq!(Some(String::from(IMMUTABLE_CACHE_CONTROL)), header(&response, header::CACHE_CONTROL));
+
+        let response = fetch("1.0.0", TestRequest::with_header(header::IF_NONE_MATCH, "\"aabb\"")).unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!(Some(String::from("\"aabb\"")), header(&response, header::ETAG));
+        let response = fetch("1.0.0", TestRequest::with_header(header::IF_NONE_MATCH, "\"ccdd\"")).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = fetch("1.0.0", TestRequest::with_header(header::IF_MATCH, "\"ccdd\"")).unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+        let response = fetch("1.0.0", TestRequest::with_header(header::IF_MATCH, "\"aabb\"")).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = fetch("1.0.0", TestRequest::with_header(header::RANGE, "bytes=0-4").header(header::IF_RANGE, "\"aabb\"")).unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        let response = fetch("1.0.0", TestRequest::with_header(header::RANGE, "bytes=0-4").header(header::IF_RANGE, "\"ccdd\"")).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        let error = fetch("2.0.0", TestRequest::default()).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+
+        write_file(&dir.join("crates/sérde/sérde-1.0.0.crate"), "not a crate name");
+        let req = TestRequest::default().param("name", "sérde").param("version", "1.0.0").finish();
+        let params = Path::<(String, String)>::extract(&req).unwrap();
+        assert_eq!(io::ErrorKind::NotFound, fetch_crate(&registry, req, params).unwrap_err().kind());
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn index_line(name: &str, version: &str) -> String {
+        format!(r#"{{"name":"{}","vers":"{}","deps":[],"cksum":"aa","features":{{}},"yanked":false}}"#, name, version)
     }
 
     #[test]
-    fn parse_crates_file_into_json() {
-        let file = File::open("test/crate_store/crate_metadata").unwrap();
-        let res = crates_as_json(file);
-        let expected = vec![
-            CrateMetadata{ name: String::from("test_crate"), vers: String::from("0.0.1"), cksum: String::from("aabb"), yanked: false},
-            CrateMetadata{ name: String::from("test_crate2"), vers: String::from("0.0.2"), cksum: String::from("aabbb"), yanked: true}];
+    fn filter_index_lines_by_policies() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-filter-index-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let mut registry_config = config::Configuration::default().crate_registry.remove(0);
+        registry_config.quarantine = Some(config::Quarantine{ hours: 24, exempt: vec![] });
+        registry_config.require_approval = true;
+        registry_config.denylist = vec![String::from("serde@1.0.1")];
+        registry_config.licenses = Some(config::Licenses{ allow: vec![], deny: vec![String::from("AGPL-*")] });
+        let advisories = config::Advisories {
+            folder: String::from("test/advisories"),
+            upstream: None,
+            branch: None,
+            local: true,
+            update_interval: 3600,
+            policy: AdvisoryPolicy::Yank,
+            registries: Vec::new(),
+        };
+        let mut registry = served_registry(&dir, registry_config);
+        registry.advisories = Some(advisories::load(&advisories));
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * 3600);
+        let names = ["serde", "memoffset", "evil"].iter().map(|name| String::from(*name)).collect::<Vec<_>>();
+        approval::decide(&registry.uri, &names, Approval::Approved).unwrap();
+        std_fs::write(quarantine::first_seen_file(&registry.uri), format!("\nserde 2.0.0 {}\n", 999 * 3600)).unwrap();
+        registry.licenses.as_ref().unwrap().record("evil", "0.1.0", Some("AGPL-3.0")).unwrap();
+        write_file(&path::Path::new(&registry.folder).join("evil/evil-0.2.0.crate"), "not a tarball");
+        registry.check_license("evil", "0.2.0");
+        registry.reload();
+
+        let lines = vec![
+            index_line("serde", "1.0.0"),
+            index_line("serde", "1.0.1"),
+            index_line("serde", "2.0.0"),
+            index_line("memoffset", "0.4.9"),
+            index_line("memoffset", "0.5.0"),
+            index_line("evil", "0.1.0"),
+            index_line("evil", "0.2.0"),
+            index_line("tokio", "1.0.0"),
+            String::from("not an index entry"),
+        ];
+        let served = registry.filter_index_lines(lines, now).iter()
+            .map(|line| IndexEntry::parse(line).unwrap())
+            .map(|entry| (entry.name, entry.vers, entry.yanked))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![
+            (String::from("serde"), String::from("1.0.0"), false),
+            // Denied by the denylist
+            (String::from("serde"), String::from("1.0.1"), true),
+            // Affected by an advisory under the yank policy
+            (String::from("memoffset"), String::from("0.4.9"), true),
+            (String::from("memoffset"), String::from("0.5.0"), false),
+        ], served);
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remove_denied_crates_from_store() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-remove-denied-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let mut registry_config = config::Configuration::default().crate_registry.remove(0);
+        registry_config.denylist = vec![String::from("inflector@0.11.3")];
+        let registry = served_registry(&dir, registry_config);
+        denylist::add_entry(&registry.uri, "evil").unwrap();
+        let crate_files = ["Inflector/Inflector-0.11.3.crate", "Inflector/Inflector-0.11.4.crate", "evil/evil-0.1.0.crate", "serde/serde-1.0.0.crate"];
+        for crate_file in crate_files.iter() {
+            write_file(&dir.join("crates").join(crate_file), "crate contents");
+        }
+        registry.reload();
+
+        registry.remove_denied_crates();
+
+        let remaining = crate_files.iter().filter(|crate_file| dir.join("crates").join(crate_file).exists()).cloned().collect::<Vec<_>>();
+        assert_eq!(vec!["Inflector/Inflector-0.11.4.crate", "serde/serde-1.0.0.crate"], remaining);
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn serve_index_view_of_stored_versions() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-serve-index-view-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let mut registry_config = config::Configuration::default().crate_registry.remove(0);
+        registry_config.partial_index = Some(dir.join("view").to_str().unwrap().to_string());
+        let registry = served_registry(&dir, registry_config.clone());
+        registry_config.uri = registry.uri.clone();
+        write_file(&path::Path::new(&registry.uri).join("config.json"), "{}");
+        write_file(&path::Path::new(&registry.uri).join(index_file_path("serde").unwrap()),
+            &format!("{}\n{}\n", index_line("serde", "1.0.0"), index_line("serde", "1.0.1")));
+        write_file(&dir.join("crates/serde/serde-1.0.0.crate"), "crate contents");
+        let fetch = |file: &'static str| {
+            let req = TestRequest::default().param("file", file).finish();
+            let params = Path::<String>::extract(&req).unwrap();
+            fetch_index_file(&registry, req, params)
+        };
+        let body = |response: HttpResponse| match *response.body() {
+            Body::Binary(ref binary) => String::from_utf8_lossy(binary.as_ref()).into_owned(),
+            _ => String::new(),
+        };
+
+        // Before the first crawl, the whole index is served
+        let response = fetch("se/rd/serde").unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+
+        update_index_view(&registry_config, &registry, |_| false).unwrap();
+
+        let response = fetch("se/rd/serde").unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(format!("{}\n", index_line("serde", "1.0.0")), body(response));
+        assert_eq!("{}", body(fetch("config.json").unwrap()));
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Serves a single request with `response`, returning its URL
+    fn serve_once(response: Vec<u8>) -> String {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/download", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = io::Read::read(&mut connection, &mut request);
+            let _ = connection.write_all(&response);
+        });
+        url
+    }
+
+    fn download(folder: &path::Path, response: Vec<u8>, checksum: &str) -> io::Result<bool> {
+        let throttle = Throttle::new(&config::Configuration::default().crate_store.crawling);
+        download_crate(&reqwest::Client::new(), &throttle, folder.to_str().unwrap().to_string(), serve_once(response),
+            String::from("serde"), String::from("1.0.0"), String::from(checksum))
+    }
+
+    #[test]
+    fn verify_downloaded_crates() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-download-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let crate_file = dir.join("serde/serde-1.0.0.crate");
+        let contents = b"crate contents";
+        let checksum = hex::encode(Sha256::digest(contents));
+        let response = |body: &[u8]| [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes(), body].concat();
+
+        let error = download(&dir, response(b"tampered contents"), &checksum).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert!(!crate_file.exists());
+        assert_eq!(0, read_dir(dir.join("serde")).unwrap().count());
+
+        assert!(download(&dir, response(contents), &checksum).unwrap());
+        assert_eq!(contents.to_vec(), std_fs::read(&crate_file).unwrap());
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn discard_interrupted_downloads() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-interrupted-download-{}", process::id()));
+        let _ = std_fs::remove_dir_all(&dir);
+        let crate_file = dir.join("serde/serde-1.0.0.crate");
+        let contents = [7u8; 100];
+        let checksum = hex::encode(Sha256::digest(&contents));
+        let headers = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n".to_vec();
+
+        // The connection closes after a tenth of the crate
+        assert!(download(&dir, [&headers[..], &contents[..10]].concat(), &checksum).is_err());
+        assert!(!crate_file.exists());
+        assert_eq!(0, read_dir(dir.join("serde")).unwrap().count());
+
+        // A download abandoned on shutdown leaves its partial file behind, which the next download replaces
+        write_file(&dir.join("serde/serde-1.0.0.partial"), "partial");
+        assert!(!crate_exists(dir.to_str().unwrap(), "serde", "1.0.0"));
+        assert!(download(&dir, [&headers[..], &contents[..]].concat(), &checksum).unwrap());
+        assert_eq!(contents.to_vec(), std_fs::read(&crate_file).unwrap());
+        assert_eq!(1, read_dir(dir.join("serde")).unwrap().count());
+        std_fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_index_file_entries() {
+        let crate_entries = index_entry::read_index_file(path::Path::new("test/crate_store/crate_metadata")).unwrap();
+        let versions = crate_entries.iter()
+            .map(|crate_entry| (crate_entry.name.as_str(), crate_entry.vers.as_str(), crate_entry.cksum.as_str(), crate_entry.yanked))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("test_crate", "0.0.1", "aabb", false), ("test_crate2", "0.0.2", "aabbb", true)], versions);
+    }
+
+    #[test]
+    fn build_crate_download_uri() {
+        assert_eq!(
+            "https://crates.io/api/v1/crates/serde/1.0.0/download",
+            crate_download_uri("https://crates.io/api/v1/crates/", "serde", "1.0.0"));
         assert_eq!(
-            expected,
-            res);
+            "https://dl.vendor.com/serde-1.0.0.crate",
+            crate_download_uri("https://dl.vendor.com/{crate}-{version}.crate", "serde", "1.0.0"));
     }
 }
@@ -0,0 +1,205 @@
+use reloadable::{self, ReloadableFile};
+use semver::{Version, VersionReq};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+enum DeniedVersions {
+    Exact(Version),
+    Matching(VersionReq),
+}
+
+/// Versions of a crate which are taken down, as `name@version`, `name@semver-req` or `name` for all versions
+#[derive(Debug, Clone, PartialEq)]
+pub struct DenyEntry {
+    name: String,
+    versions: DeniedVersions,
+}
+
+impl DenyEntry {
+    pub fn parse(entry: &str) -> Result<DenyEntry, String> {
+        let mut parts = entry.trim().splitn(2, '@');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Invalid crate name in {:?}", entry));
+        }
+        let versions = match parts.next().map(str::trim) {
+            // A plain version is an exact version, not the caret requirement it would be in Cargo.toml
+            Some(version) => match Version::parse(version) {
+                Ok(version) => DeniedVersions::Exact(version),
+                Err(_) => VersionReq::parse(version)
+                    .map(DeniedVersions::Matching)
+                    .map_err(|e| format!("Invalid version requirement in {:?}: {:?}", entry, e))?,
+            },
+            None => DeniedVersions::Matching(VersionReq::any()),
+        };
+        Ok(DenyEntry { name, versions })
+    }
+
+    pub fn matches(&self, name: &str, version: &str) -> bool {
+        if self.name != name.to_lowercase() {
+            return false;
+        }
+        match (&self.versions, Version::parse(version)) {
+            (DeniedVersions::Exact(denied), Ok(ref version)) => denied == version,
+            (DeniedVersions::Matching(requirement), Ok(ref version)) => requirement.matches(version),
+            // Versions which aren't valid semver can only be denied as a whole crate
+            (DeniedVersions::Matching(requirement), Err(_)) => *requirement == VersionReq::any(),
+            (DeniedVersions::Exact(_), Err(_)) => false,
+        }
+    }
+}
+
+/// Location of the denylist managed through the admin API, next to the git metadata of the registry
+pub fn denylist_file(registry_uri: &str) -> PathBuf {
+    Path::new(registry_uri).join(".git").join("mirage-denylist")
+}
+
+fn read_entries(path: &Path) -> io::Result<Vec<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    BufReader::new(file)
+    .lines()
+    .filter(|line| line.as_ref().map(|line| !line.trim().is_empty()).unwrap_or(true))
+    .map(|line| line.map(|line| String::from(line.trim())))
+    .collect()
+}
+
+fn write_entries(path: &Path, entries: &[String]) -> io::Result<()> {
+    reloadable::write_atomically(path, |file| entries.iter().try_for_each(|entry| writeln!(file, "{}", entry)))
+}
+
+/// Adds an entry to the managed denylist, returns whether it was new
+pub fn add_entry(registry_uri: &str, entry: &str) -> io::Result<bool> {
+    DenyEntry::parse(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let path = denylist_file(registry_uri);
+    let mut entries = read_entries(&path)?;
+    if entries.iter().any(|existing| existing == entry.trim()) {
+        return Ok(false);
+    }
+    entries.push(String::from(entry.trim()));
+    write_entries(&path, &entries).map(|()| true)
+}
+
+/// Removes an entry from the managed denylist, returns whether it existed
+pub fn remove_entry(registry_uri: &str, entry: &str) -> io::Result<bool> {
+    let path = denylist_file(registry_uri);
+    let entries = read_entries(&path)?;
+    let remaining = entries.iter()
+        .filter(|existing| *existing != entry.trim())
+        .cloned()
+        .collect::<Vec<_>>();
+    if remaining.len() == entries.len() {
+        return Ok(false);
+    }
+    write_entries(&path, &remaining).map(|()| true)
+}
+
+// Entries as written, with their parsed form
+type Entries = Vec<(String, DenyEntry)>;
+
+/// Denylist of the configuration & of the admin API, the latter is reloaded whenever it is modified
+pub struct Denylist {
+    configured: Entries,
+    managed: ReloadableFile<Entries>,
+}
+
+fn parse_entries(entries: Vec<String>) -> Entries {
+    entries.into_iter()
+        .filter_map(|entry| match DenyEntry::parse(&entry) {
+            Ok(parsed) => Some((entry, parsed)),
+            Err(e) => {
+                eprintln!("Ignoring denylist entry: {}", e);
+                None
+            },
+        })
+        .collect()
+}
+
+fn read_managed_entries(path: &Path) -> io::Result<Entries> {
+    read_entries(path).map(parse_entries)
+}
+
+impl Denylist {
+    /// Invalid entries are left out, whether they are configured or managed
+    pub fn new(registry_uri: &str, configured: &[String]) -> Denylist {
+        Denylist {
+            configured: parse_entries(configured.to_vec()),
+            managed: ReloadableFile::new(denylist_file(registry_uri), read_managed_entries),
+        }
+    }
+
+    pub fn reload(&self) {
+        self.managed.reload_if_modified();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configured.is_empty() && self.managed.with(Vec::is_empty)
+    }
+
+    pub fn is_denied(&self, name: &str, version: &str) -> bool {
+        self.configured.iter().any(|(_, entry)| entry.matches(name, version))
+            || self.managed.with(|managed| managed.iter().any(|(_, entry)| entry.matches(name, version)))
+    }
+
+    /// All entries, the configured ones first
+    pub fn entries(&self) -> Vec<String> {
+        let managed = self.managed.with(Entries::clone);
+        self.configured.iter().chain(managed.iter()).map(|(entry, _)| entry.clone()).collect()
+    }
+
+    /// Crate names with denied versions
+    pub fn names(&self) -> Vec<String> {
+        let managed = self.managed.with(Entries::clone);
+        let mut names = self.configured.iter().chain(managed.iter()).map(|(_, entry)| entry.name.clone()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn match_denied_versions() {
+        let exact = DenyEntry::parse("serde@1.0.0").unwrap();
+        assert!(exact.matches("serde", "1.0.0"));
+        assert!(!exact.matches("serde", "1.0.1"));
+        let requirement = DenyEntry::parse("Tokio@>=1.2, <1.4").unwrap();
+        assert!(requirement.matches("tokio", "1.3.7"));
+        assert!(!requirement.matches("tokio", "1.4.0"));
+        assert!(!requirement.matches("tokio-util", "1.3.0"));
+        let whole_crate = DenyEntry::parse("evil").unwrap();
+        assert!(whole_crate.matches("evil", "0.1.0"));
+        assert!(whole_crate.matches("evil", "not-semver"));
+        assert!(DenyEntry::parse("serde@not a version").is_err());
+        assert!(DenyEntry::parse("@1.0.0").is_err());
+    }
+
+    #[test]
+    fn skip_invalid_entries() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-denylist-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let uri = dir.to_str().unwrap();
+        let denylist = Denylist::new(uri, &[String::from("serde@not a version"), String::from("Evil")]);
+        fs::write(denylist_file(uri), "@1.0.0\ntokio@1.0.0\n").unwrap();
+        denylist.reload();
+
+        assert_eq!(vec![String::from("Evil"), String::from("tokio@1.0.0")], denylist.entries());
+        assert_eq!(vec![String::from("evil"), String::from("tokio")], denylist.names());
+        assert!(denylist.is_denied("evil", "0.1.0"));
+        assert!(!denylist.is_denied("serde", "1.0.0"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
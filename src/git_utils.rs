@@ -1,5 +1,54 @@
-use git2::{Repository, build::CheckoutBuilder, MergeOptions, FileFavor, IndexAddOption, AnnotatedCommit, Error, Oid, BranchType, ObjectType};
+use config::GitTransport;
+use git2::{Repository, Config, Cred, CredentialType, ErrorCode, FetchOptions, ProxyOptions, RemoteCallbacks, build::{CheckoutBuilder, RepoBuilder}, MergeOptions, FileFavor, IndexAddOption, AnnotatedCommit, Error, Oid, Commit, ResetType, Signature};
+use std::ffi::CString;
+use std::io;
 use std::iter;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Credentials offered per connection before giving up, libgit2 asks again as long as they're rejected
+const MAX_CREDENTIAL_ATTEMPTS : u32 = 3;
+// Seconds without progress after which a fetch is given up, kept in the repository configuration like git does
+const LOW_SPEED_TIME : &str = "http.lowSpeedTime";
+// Interval at which a fetch is checked for progress
+const FETCH_POLL_INTERVAL_MILLIS : u64 = 200;
+// Proxy of the upstream, kept in the repository configuration like git does
+const ORIGIN_PROXY : &str = "remote.origin.proxy";
+// `GIT_OPT_SET_SSL_CERT_LOCATIONS` of `git_libgit2_opt_t`, which git2 doesn't expose. The options are only ever
+// appended to the enum, so its value is the same from libgit2 0.24 to 0.27.0, the version bundled by libgit2-sys 0.7.
+const GIT_OPT_SET_SSL_CERT_LOCATIONS : c_int = 12;
+const MIN_LIBGIT2_VERSION_WITH_CERT_LOCATIONS : (c_int, c_int) = (0, 24);
+
+extern "C" {
+    fn git_libgit2_init() -> c_int;
+    fn git_libgit2_version(major: *mut c_int, minor: *mut c_int, rev: *mut c_int);
+    fn git_libgit2_opts(option: c_int, ...) -> c_int;
+}
+
+/// Adds the certificate authorities of a PEM file to the global TLS settings of libgit2,
+/// checking first that the linked libgit2 has the option where it is expected
+fn set_ssl_cert_file(file: &CString) -> Result<(), Error> {
+    let (mut major, mut minor, mut rev) = (0, 0, 0);
+    // The TLS settings are only created by the initialization of libgit2, which is reference counted
+    let result = unsafe {
+        git_libgit2_init();
+        git_libgit2_version(&mut major, &mut minor, &mut rev);
+        if (major, minor) < MIN_LIBGIT2_VERSION_WITH_CERT_LOCATIONS {
+            return Err(Error::from_str(&format!("libgit2 {}.{}.{} can't trust extra certificate authorities", major, minor, rev)));
+        }
+        git_libgit2_opts(GIT_OPT_SET_SSL_CERT_LOCATIONS, file.as_ptr(), ptr::null::<c_char>())
+    };
+    if result < 0 {
+        Err(Error::last_error(result).unwrap_or_else(|| Error::from_str("Unknown libgit2 error")))
+    } else {
+        Ok(())
+    }
+}
 
 pub fn force_merge_remote_commit<'a>(repo: &Repository, remote_commit: AnnotatedCommit<'a>) -> Result<Option<AnnotatedCommit<'a>>, Error> {
     let mut checkout_opts = CheckoutBuilder::new();
@@ -17,18 +66,185 @@ pub fn force_merge_remote_commit<'a>(repo: &Repository, remote_commit: Annotated
     remote_commit_opt
 }
 
-// TODO: fix FF merge, still consolidates into single commit
-pub fn fast_forward_merge<'a>(repo: &Repository, remote_commmit_id: Oid) -> Result<Option<AnnotatedCommit<'a>>, Error> {
-    repo.find_branch("origin/master", BranchType::Remote)
-    .and_then(|remote_branch| remote_branch.into_reference().peel(ObjectType::Tree))
-    .and_then(|tree| repo.checkout_tree(&tree, None))
-    .and_then(|()| repo.head())
-    .and_then(|mut head| head.set_target(remote_commmit_id, "fast-forward to remote master"))
-    .and_then(|_| { 
-        Ok(None)
+/// Callbacks providing the configured credentials, or the ones of the SSH agent & git credential helpers
+fn remote_callbacks<'a>(transport: &'a GitTransport) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut attempts = 0;
+    callbacks.credentials(move |url, url_username, allowed| {
+        attempts += 1;
+        if attempts > MAX_CREDENTIAL_ATTEMPTS {
+            return Err(Error::from_str(&format!("Authentication to {} failed", url)));
+        }
+        let username = transport.username.as_deref().or(url_username).unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            Cred::username(username)
+        } else if allowed.contains(CredentialType::SSH_KEY) {
+            match transport.ssh_key {
+                Some(ref private_key) => Cred::ssh_key(username,
+                    transport.ssh_public_key.as_ref().map(Path::new),
+                    Path::new(private_key),
+                    transport.ssh_passphrase.as_deref()),
+                None => Cred::ssh_key_from_agent(username),
+            }
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            match transport.token {
+                Some(ref token) => Cred::userpass_plaintext(username, token),
+                None => Config::open_default().and_then(|config| Cred::credential_helper(&config, url, url_username)),
+            }
+        } else {
+            Cred::default()
+        }
+    });
+    callbacks
+}
+
+fn fetch_options<'a>(transport: &'a GitTransport, proxy: Option<&str>) -> FetchOptions<'a> {
+    fetch_options_with_callbacks(remote_callbacks(transport), proxy)
+}
+
+fn fetch_options_with_callbacks<'a>(callbacks: RemoteCallbacks<'a>, proxy: Option<&str>) -> FetchOptions<'a> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    if let Some(proxy) = proxy {
+        let mut proxy_options = ProxyOptions::new();
+        proxy_options.url(proxy);
+        fetch_options.proxy_options(proxy_options);
+    }
+    fetch_options
+}
+
+/// Sets the proxy of the `origin` remote, or removes it
+pub fn set_origin_proxy(repo: &Repository, proxy: Option<&str>) -> Result<(), Error> {
+    let mut config = repo.config()?;
+    match proxy {
+        Some(proxy) => config.set_str(ORIGIN_PROXY, proxy),
+        None => match config.remove(ORIGIN_PROXY) {
+            Err(ref e) if e.code() == ErrorCode::NotFound => Ok(()),
+            result => result,
+        },
+    }
+}
+
+/// Trusts the certificate authorities of a PEM file for all HTTPS connections of libgit2,
+/// in addition to the ones of the system. The TLS settings of libgit2 are global, so this is done once on startup.
+pub fn trust_ca_bundle(ca_bundle: &str) -> Result<(), Error> {
+    let file = CString::new(ca_bundle).map_err(|_| Error::from_str("Invalid CA bundle path"))?;
+    set_ssl_cert_file(&file)
+        .map_err(|e| Error::from_str(&format!("Could not load the CA bundle {}: {}", ca_bundle, e.message())))
+}
+
+/// Clones `url` into `path`, authenticating with `transport` & connecting through `proxy` from now on
+pub fn clone_repo(url: &str, path: &str, transport: &GitTransport, proxy: Option<&str>) -> Result<Repository, Error> {
+    let repo = RepoBuilder::new()
+        .fetch_options(fetch_options(transport, proxy))
+        .clone(url, Path::new(path))?;
+    set_origin_proxy(&repo, proxy)?;
+    Ok(repo)
+}
+
+/// Sets the time without progress after which a fetch is given up
+pub fn set_stall_timeout(repo: &Repository, timeout: Duration) -> Result<(), Error> {
+    repo.config()?.set_i64(LOW_SPEED_TIME, timeout.as_secs() as i64)
+}
+
+/// Fetches `branch` from the `origin` remote, which is created from `url` if missing.
+/// libgit2 has no timeouts, so the fetch runs on its own thread & is given up once it made no progress
+/// for the stall timeout of the repository. A stalled fetch is left behind until the TCP timeouts of the system end it.
+pub fn fetch_origin_branch(repo: &Repository, url: &str, branch: &str, transport: &GitTransport) -> Result<(), Error> {
+    if let Err(e) = repo.find_remote("origin") {
+        if e.code() != ErrorCode::NotFound {
+            return Err(e);
+        }
+        repo.remote("origin", url)?;
+    }
+    let config = repo.config()?;
+    let proxy = config.get_string(ORIGIN_PROXY).ok();
+    let stall_timeout = config.get_i64(LOW_SPEED_TIME).ok().filter(|secs| *secs > 0).map(|secs| Duration::from_secs(secs as u64));
+
+    let last_progress = Arc::new(Mutex::new(Instant::now()));
+    let (path, branch, transport) = (repo.path().to_path_buf(), String::from(branch), transport.clone());
+    let fetch_progress = last_progress.clone();
+    let (fetched, result) = mpsc::channel();
+    thread::spawn(move || {
+        let fetch = || -> Result<(), Error> {
+            let repo = Repository::open(&path)?;
+            let mut remote = repo.find_remote("origin")?;
+            let mut callbacks = remote_callbacks(&transport);
+            let (transfer_progress, sideband_progress) = (fetch_progress.clone(), fetch_progress);
+            callbacks.transfer_progress(move |_| record_progress(&transfer_progress));
+            callbacks.sideband_progress(move |_| record_progress(&sideband_progress));
+            remote.fetch(&[&branch], Some(&mut fetch_options_with_callbacks(callbacks, proxy.as_deref())), None)
+        };
+        let _ = fetched.send(fetch());
+    });
+    loop {
+        match result.recv_timeout(Duration::from_millis(FETCH_POLL_INTERVAL_MILLIS)) {
+            Ok(fetched) => return fetched,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Some(stall_timeout) = stall_timeout {
+                    let stalled = last_progress.lock().map(|last_progress| last_progress.elapsed() > stall_timeout).unwrap_or(true);
+                    if stalled {
+                        return Err(Error::from_str(&format!("Fetch of {} made no progress for {}s", url, stall_timeout.as_secs())));
+                    }
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::from_str(&format!("Fetch of {} failed", url))),
+        }
+    }
+}
+
+fn record_progress(last_progress: &Mutex<Instant>) -> bool {
+    if let Ok(mut last_progress) = last_progress.lock() {
+        *last_progress = Instant::now();
+    }
+    true
+}
+
+/// Moves the current branch forward to `target` in a single reference update, then updates the working directory
+pub fn fast_forward_merge(repo: &Repository, target: Oid) -> Result<(), Error> {
+    repo.head()
+    .and_then(|mut head| head.set_target(target, "fast-forward to remote master"))
+    .and_then(|_| {
+        let mut checkout_opts = CheckoutBuilder::new();
+        checkout_opts.force().remove_untracked(true);
+        repo.checkout_head(Some(&mut checkout_opts))
     })
 }
 
+/// Commits `contents` as a file at the root of the tree of `parent`, without touching the index or working directory.
+/// No reference is updated, so the returned commit is dangling until a reference is moved to it.
+pub fn commit_file_onto(repo: &Repository, parent: &Commit, file_name: &str, contents: &[u8], signature: &Signature, message: &str) -> Result<Oid, Error> {
+    let blob_id = repo.blob(contents)?;
+    let mut tree_builder = repo.treebuilder(Some(&parent.tree()?))?;
+    tree_builder.insert(file_name, blob_id, 0o100_644)?;
+    let tree = repo.find_tree(tree_builder.write()?)?;
+    repo.commit(None, signature, signature, message, &tree, &[parent])
+}
+
+/// Moves the current branch to `commit`, discarding the local history & working directory changes
+pub fn reset_to_commit(repo: &Repository, commit: &Commit) -> Result<(), Error> {
+    let mut checkout_opts = CheckoutBuilder::new();
+    checkout_opts.force().remove_untracked(true);
+    repo.reset(commit.as_object(), ResetType::Hard, Some(&mut checkout_opts))
+}
+
+/// Removes all objects which are no longer reachable from any reference.
+/// libgit2 doesn't support garbage collection, so this relies on the git command line tool.
+pub fn prune_unreachable_objects(repo: &Repository) -> io::Result<()> {
+    let run_git = |args: &[&str]| Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(args)
+        .status()
+        .and_then(|status| if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("git {} failed: {}", args.join(" "), status)))
+        });
+    run_git(&["reflog", "expire", "--expire=now", "--all"])
+        .and_then(|()| run_git(&["gc", "--prune=now", "--quiet"]))
+}
+
 pub fn clean_working_dir(repo: &Repository) -> Result<(), Error> {
     repo.index()
     .and_then(|mut index| {
@@ -41,4 +257,31 @@ pub fn clean_working_dir(repo: &Repository) -> Result<(), Error> {
         .and_then(|()| index.add_all(iter::once("*"), IndexAddOption::FORCE, None))
         .and_then(|()| index.write())
     })
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::process;
+
+    #[test]
+    fn give_up_stalled_fetches() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-stalled-fetch-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // Accepts the connections, but never answers
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/index.git", upstream.local_addr().unwrap());
+        let repo = Repository::init(&dir).unwrap();
+        set_stall_timeout(&repo, Duration::from_secs(1)).unwrap();
+
+        let started = Instant::now();
+        let error = fetch_origin_branch(&repo, &url, "master", &GitTransport::default()).unwrap_err();
+        assert!(error.message().contains("made no progress"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(upstream);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{self, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// Keys of an object in the order of the line it was parsed from, with the values of the keys which aren't modelled
+// & the nulls which stood for a default value
+type Layout = Vec<(String, Option<Value>)>;
+
+const ENTRY_KEYS : &[&str] = &["name", "vers", "deps", "cksum", "features", "features2", "yanked", "links", "rust_version", "v"];
+const DEPENDENCY_KEYS : &[&str] = &["name", "req", "features", "optional", "default_features", "target", "kind", "registry", "package"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+/// Features & the features or dependencies they enable, in the order of the index line
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Features(pub Vec<(String, Vec<String>)>);
+
+impl Features {
+    pub fn get(&self, feature: &str) -> Option<&Vec<String>> {
+        self.0.iter().find(|(name, _)| name == feature).map(|(_, enables)| enables)
+    }
+}
+
+impl Serialize for Features {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (name, enables) in &self.0 {
+            map.serialize_entry(name, enables)?;
+        }
+        map.end()
+    }
+}
+
+struct FeaturesVisitor;
+
+impl<'de> Visitor<'de> for FeaturesVisitor {
+    type Value = Features;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of features")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Features, A::Error> {
+        let mut features = Vec::new();
+        while let Some(name) = map.next_key::<String>()? {
+            features.push((name, map.next_value()?));
+        }
+        Ok(Features(features))
+    }
+}
+
+impl<'de> Deserialize<'de> for Features {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Features, D::Error> {
+        deserializer.deserialize_map(FeaturesVisitor)
+    }
+}
+
+/// Dependency of a version, as in the `deps` of an index entry
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dependency {
+    pub name: String,
+    pub req: String,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    pub kind: Option<DependencyKind>,
+    pub registry: Option<String>, // Index of the registry of the dependency, the registry of the entry if `None`
+    pub package: Option<String>, // Name of the dependency in its registry, when it is renamed
+    layout: Layout,
+}
+
+impl Dependency {
+    /// Name of the crate in its registry
+    pub fn crate_name(&self) -> &str {
+        self.package.as_ref().unwrap_or(&self.name)
+    }
+
+    pub fn kind(&self) -> DependencyKind {
+        self.kind.unwrap_or(DependencyKind::Normal)
+    }
+}
+
+/// Index entry of a version, i.e. a line of an index file.
+/// Keys are written in the order they were parsed in, unknown keys included,
+/// so that lines written by the upstream registry round-trip byte-for-byte.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IndexEntry {
+    pub name: String,
+    pub vers: String,
+    pub deps: Vec<Dependency>,
+    pub cksum: String,
+    pub features: Features,
+    pub features2: Option<Features>, // Features using the `dep:` or `?` syntax
+    pub yanked: bool,
+    pub links: Option<String>,
+    pub rust_version: Option<String>,
+    pub v: Option<u32>, // Schema version of the entry, 1 if `None`
+    layout: Layout,
+}
+
+impl IndexEntry {
+    pub fn parse(line: &str) -> Result<IndexEntry, serde_json::Error> {
+        serde_json::from_str(line)
+    }
+
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("Could not serialize index entry")
+    }
+}
+
+/// Keys to write: the parsed keys in their order, then the canonical keys which were set to a value other than
+/// their default since, so that the keys a line didn't have aren't added to it
+fn layout_keys<'a, F>(layout: &'a Layout, canonical: &'a [&str], is_set: F) -> Vec<(&'a str, Option<&'a Value>)>
+    where F: Fn(&str) -> bool {
+    let mut keys = layout.iter().map(|(key, value)| (key.as_str(), value.as_ref())).collect::<Vec<_>>();
+    keys.extend(canonical.iter()
+        .filter(|key| is_set(key) && !layout.iter().any(|(parsed, _)| parsed == *key))
+        .map(|key| (*key, None)));
+    keys
+}
+
+impl Serialize for Dependency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let keys = layout_keys(&self.layout, DEPENDENCY_KEYS, |key| match key {
+            "features" => !self.features.is_empty(),
+            "optional" => self.optional,
+            "default_features" => !self.default_features,
+            "target" => self.target.is_some(),
+            "kind" => self.kind.is_some(),
+            "registry" => self.registry.is_some(),
+            "package" => self.package.is_some(),
+            _ => true,
+        });
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for (key, unknown) in keys {
+            match (key, unknown) {
+                (_, Some(value)) => map.serialize_entry(key, value)?,
+                ("name", _) => map.serialize_entry(key, &self.name)?,
+                ("req", _) => map.serialize_entry(key, &self.req)?,
+                ("features", _) => map.serialize_entry(key, &self.features)?,
+                ("optional", _) => map.serialize_entry(key, &self.optional)?,
+                ("default_features", _) => map.serialize_entry(key, &self.default_features)?,
+                ("target", _) => map.serialize_entry(key, &self.target)?,
+                ("kind", _) => map.serialize_entry(key, &self.kind)?,
+                ("registry", _) => map.serialize_entry(key, &self.registry)?,
+                ("package", _) => map.serialize_entry(key, &self.package)?,
+                _ => (),
+            }
+        }
+        map.end()
+    }
+}
+
+struct DependencyVisitor;
+
+impl<'de> Visitor<'de> for DependencyVisitor {
+    type Value = Dependency;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a dependency")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Dependency, A::Error> {
+        let mut dependency = Dependency { default_features: true, ..Dependency::default() };
+        let (mut name, mut req) = (None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            let mut unknown = None;
+            match key.as_str() {
+                "name" => name = Some(map.next_value()?),
+                "req" => req = Some(map.next_value()?),
+                "features" => dependency.features = map.next_value()?,
+                "optional" => dependency.optional = map.next_value()?,
+                "default_features" => dependency.default_features = map.next_value()?,
+                "target" => dependency.target = map.next_value()?,
+                "kind" => dependency.kind = map.next_value()?,
+                "registry" => dependency.registry = map.next_value()?,
+                "package" => dependency.package = map.next_value()?,
+                _ => unknown = Some(map.next_value()?),
+            }
+            dependency.layout.push((key, unknown));
+        }
+        dependency.name = name.ok_or_else(|| de::Error::missing_field("name"))?;
+        dependency.req = req.ok_or_else(|| de::Error::missing_field("req"))?;
+        Ok(dependency)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dependency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Dependency, D::Error> {
+        deserializer.deserialize_map(DependencyVisitor)
+    }
+}
+
+impl Serialize for IndexEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let keys = layout_keys(&self.layout, ENTRY_KEYS, |key| match key {
+            "deps" => !self.deps.is_empty(),
+            "features" => !self.features.0.is_empty(),
+            "features2" => self.features2.is_some(),
+            "yanked" => self.yanked,
+            "links" => self.links.is_some(),
+            "rust_version" => self.rust_version.is_some(),
+            "v" => self.v.is_some(),
+            _ => true,
+        });
+        let mut map = serializer.serialize_map(Some(keys.len()))?;
+        for (key, unknown) in keys {
+            match (key, unknown) {
+                // A null yanked is kept as long as the version isn't yanked
+                ("yanked", Some(Value::Null)) if !self.yanked => map.serialize_entry(key, &Value::Null)?,
+                ("yanked", _) => map.serialize_entry(key, &self.yanked)?,
+                (_, Some(value)) => map.serialize_entry(key, value)?,
+                ("name", _) => map.serialize_entry(key, &self.name)?,
+                ("vers", _) => map.serialize_entry(key, &self.vers)?,
+                ("deps", _) => map.serialize_entry(key, &self.deps)?,
+                ("cksum", _) => map.serialize_entry(key, &self.cksum)?,
+                ("features", _) => map.serialize_entry(key, &self.features)?,
+                ("features2", _) => map.serialize_entry(key, &self.features2)?,
+                ("links", _) => map.serialize_entry(key, &self.links)?,
+                ("rust_version", _) => map.serialize_entry(key, &self.rust_version)?,
+                ("v", _) => map.serialize_entry(key, &self.v)?,
+                _ => (),
+            }
+        }
+        map.end()
+    }
+}
+
+struct IndexEntryVisitor;
+
+impl<'de> Visitor<'de> for IndexEntryVisitor {
+    type Value = IndexEntry;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an index entry")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<IndexEntry, A::Error> {
+        let mut entry = IndexEntry::default();
+        let (mut name, mut vers, mut cksum) = (None, None, None);
+        while let Some(key) = map.next_key::<String>()? {
+            let mut unknown = None;
+            match key.as_str() {
+                "name" => name = Some(map.next_value()?),
+                "vers" => vers = Some(map.next_value()?),
+                "deps" => entry.deps = map.next_value()?,
+                "cksum" => cksum = Some(map.next_value()?),
+                "features" => entry.features = map.next_value()?,
+                "features2" => entry.features2 = map.next_value()?,
+                "yanked" => match map.next_value::<Option<bool>>()? {
+                    Some(yanked) => entry.yanked = yanked,
+                    None => unknown = Some(Value::Null),
+                },
+                "links" => entry.links = map.next_value()?,
+                "rust_version" => entry.rust_version = map.next_value()?,
+                "v" => entry.v = map.next_value()?,
+                _ => unknown = Some(map.next_value()?),
+            }
+            entry.layout.push((key, unknown));
+        }
+        entry.name = name.ok_or_else(|| de::Error::missing_field("name"))?;
+        entry.vers = vers.ok_or_else(|| de::Error::missing_field("vers"))?;
+        entry.cksum = cksum.ok_or_else(|| de::Error::missing_field("cksum"))?;
+        Ok(entry)
+    }
+}
+
+impl<'de> Deserialize<'de> for IndexEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<IndexEntry, D::Error> {
+        deserializer.deserialize_map(IndexEntryVisitor)
+    }
+}
+
+/// Location of the index file of a crate, relative to the root of the index,
+/// `None` if the name can't be a crate name, i.e. isn't made of ASCII letters, digits, `-` & `_`
+pub fn index_file_path(name: &str) -> Option<String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    let name = name.to_lowercase();
+    Some(match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    })
+}
+
+/// Reads the entries of an index file. Lines which can't be parsed are reported & skipped.
+pub fn read_index_file(path: &Path) -> io::Result<Vec<IndexEntry>> {
+    let file = File::open(path)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // A line which isn't UTF-8 is skipped like an invalid entry, the following lines are still read
+        let line = match line {
+            Ok(line) => line,
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Skipping unreadable index entry in {:?}: {:?}", path, e);
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match IndexEntry::parse(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => eprintln!("Skipping invalid index entry in {:?}: {}", path, e),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn round_trip_index_entries() {
+        let path = Path::new("test/index_entry/entries");
+        let mut contents = String::new();
+        File::open(path).and_then(|mut file| io::Read::read_to_string(&mut file, &mut contents)).unwrap();
+        for line in contents.lines() {
+            assert_eq!(line, IndexEntry::parse(line).unwrap().to_line());
+        }
+
+        let entries = read_index_file(path).unwrap();
+        let tokio = &entries[1];
+        assert_eq!(Some(String::from("1.63")), tokio.rust_version);
+        assert_eq!(Some(2), tokio.v);
+        let tracing = (String::from("tracing"), vec![String::from("dep:tracing")]);
+        assert_eq!(Some(Features(vec![tracing])), tokio.features2);
+        let windows = tokio.deps.iter().find(|dep| dep.name == "windows").unwrap();
+        assert_eq!(Some(String::from("windows-sys")), windows.package);
+        assert_eq!(Some(String::from("cfg(windows)")), windows.target);
+        assert!(windows.optional && !windows.default_features);
+        let loom = tokio.deps.iter().find(|dep| dep.name == "loom").unwrap();
+        assert_eq!(Some(DependencyKind::Dev), loom.kind);
+        assert_eq!(Some(String::from("https://github.com/rust-lang/crates.io-index")), loom.registry);
+        assert_eq!(Some(String::from("ring")), entries[2].links);
+    }
+
+    #[test]
+    fn skip_unreadable_index_lines() {
+        let path = env::temp_dir().join(format!("cargo-mirage-index-entries-{}", process::id()));
+        let serde = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#;
+        fs::write(&path, [serde.as_bytes(), b"\n\xff\xfe\n", serde.replace("1.0.0", "1.0.1").as_bytes(), b"\n"].concat()).unwrap();
+        let versions = read_index_file(&path).unwrap().into_iter().map(|entry| entry.vers).collect::<Vec<_>>();
+        assert_eq!(vec!["1.0.0", "1.0.1"], versions);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn locate_index_files() {
+        assert_eq!(Some(String::from("1/a")), index_file_path("a"));
+        assert_eq!(Some(String::from("2/io")), index_file_path("io"));
+        assert_eq!(Some(String::from("3/s/syn")), index_file_path("syn"));
+        assert_eq!(Some(String::from("se/rd/serde")), index_file_path("Serde"));
+        assert_eq!(None, index_file_path("sérde"));
+        assert_eq!(None, index_file_path("../config.json"));
+        assert_eq!(None, index_file_path(""));
+    }
+
+    #[test]
+    fn yank_index_entry() {
+        let line = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false,"pubtime":"2017-04-20T08:00:00Z"}"#;
+        let mut entry = IndexEntry::parse(line).unwrap();
+        entry.yanked = true;
+        entry.rust_version = Some(String::from("1.15"));
+        assert_eq!(
+            r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":true,"pubtime":"2017-04-20T08:00:00Z","rust_version":"1.15"}"#,
+            entry.to_line());
+        assert!(IndexEntry::parse(r#"{"name":"serde","vers":"1.0.0"}"#).is_err());
+    }
+
+    #[test]
+    fn round_trip_old_index_entries() {
+        let minimal = r#"{"name":"a","vers":"0.1.0","deps":[{"name":"b","req":"*"}],"cksum":"aa"}"#;
+        let mut entry = IndexEntry::parse(minimal).unwrap();
+        assert!(entry.deps[0].default_features && entry.deps[0].kind.is_none());
+        assert_eq!(minimal, entry.to_line());
+        entry.yanked = true;
+        entry.deps[0].optional = true;
+        assert_eq!(r#"{"name":"a","vers":"0.1.0","deps":[{"name":"b","req":"*","optional":true}],"cksum":"aa","yanked":true}"#, entry.to_line());
+
+        let unset = r#"{"name":"a","vers":"0.1.0","cksum":"aa","yanked":null}"#;
+        let mut entry = IndexEntry::parse(unset).unwrap();
+        assert!(!entry.yanked);
+        assert_eq!(unset, entry.to_line());
+        entry.yanked = true;
+        assert_eq!(r#"{"name":"a","vers":"0.1.0","cksum":"aa","yanked":true}"#, entry.to_line());
+    }
+}
@@ -0,0 +1,114 @@
+use git2::{Repository, Error, ErrorCode, Index, IndexEntry, IndexTime, Oid, Signature};
+use std::path::Path;
+
+/// Builds a new version of the derived index, with the files & versions added to it only
+pub struct IndexViewBuilder {
+    repo: Repository,
+    index: Index,
+}
+
+impl IndexViewBuilder {
+    /// Opens the derived index, a bare repository created on first use
+    pub fn new(view_uri: &str) -> Result<IndexViewBuilder, Error> {
+        let repo = if Path::new(view_uri).exists() {
+            Repository::open_bare(view_uri)?
+        } else {
+            Repository::init_bare(view_uri)?
+        };
+        Ok(IndexViewBuilder { repo, index: Index::new()? })
+    }
+
+    pub fn add_file(&mut self, file: &str, contents: &[u8]) -> Result<(), Error> {
+        let id = self.repo.blob(contents)?;
+        self.index.add(&IndexEntry {
+            ctime: IndexTime::new(0, 0),
+            mtime: IndexTime::new(0, 0),
+            dev: 0, ino: 0, mode: 0o100_644, uid: 0, gid: 0,
+            file_size: contents.len() as u32,
+            id,
+            flags: 0,
+            flags_extended: 0,
+            path: file.as_bytes().to_vec(),
+        })
+    }
+
+    /// Commits the added files as the new version of the derived index, unless nothing changed
+    pub fn commit(mut self) -> Result<Option<Oid>, Error> {
+        let tree = self.repo.find_tree(self.index.write_tree_to(&self.repo)?)?;
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(ref e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        if parent.as_ref().map(|parent| parent.tree_id() == tree.id()).unwrap_or(false) {
+            return Ok(None);
+        }
+        let signature = Signature::now("cargo-mirage", "cargo-mirage@localhost")?;
+        let parents = parent.iter().collect::<Vec<_>>();
+        self.repo.commit(Some("HEAD"), &signature, &signature, "Update the versions in the crate store", &tree, &parents).map(Some)
+    }
+}
+
+/// Whether a first version of the derived index was committed
+pub fn is_committed(view_uri: &str) -> bool {
+    Repository::open_bare(view_uri).map(|repo| repo.head().is_ok()).unwrap_or(false)
+}
+
+/// Contents & object id of a file of the derived index, `None` if it isn't part of it
+pub fn read_view_file(view_uri: &str, file: &str) -> Result<Option<(Oid, Vec<u8>)>, Error> {
+    let repo = match Repository::open_bare(view_uri) {
+        Ok(repo) => repo,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let tree = match repo.head() {
+        Ok(head) => head.peel_to_tree()?,
+        Err(ref e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let entry = match tree.get_path(Path::new(file)) {
+        Ok(entry) => entry,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let object = entry.to_object(&repo)?;
+    Ok(object.as_blob().map(|blob| (blob.id(), blob.content().to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn commit_index_views() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-index-view-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let uri = dir.to_str().unwrap();
+        assert!(!is_committed(uri));
+        let serde = b"{\"name\":\"serde\",\"vers\":\"1.0.0\",\"deps\":[],\"cksum\":\"aa\",\"features\":{},\"yanked\":false}\n";
+
+        let mut builder = IndexViewBuilder::new(uri).unwrap();
+        builder.add_file("config.json", b"{}").unwrap();
+        builder.add_file("se/rd/serde", serde).unwrap();
+        let first = builder.commit().unwrap();
+        assert!(first.is_some());
+        assert!(is_committed(uri));
+        assert_eq!(serde.to_vec(), read_view_file(uri, "se/rd/serde").unwrap().unwrap().1);
+
+        let mut unchanged = IndexViewBuilder::new(uri).unwrap();
+        unchanged.add_file("config.json", b"{}").unwrap();
+        unchanged.add_file("se/rd/serde", serde).unwrap();
+        assert_eq!(None, unchanged.commit().unwrap());
+
+        let mut emptied = IndexViewBuilder::new(uri).unwrap();
+        emptied.add_file("config.json", b"{}").unwrap();
+        assert!(emptied.commit().unwrap().is_some());
+        assert_eq!(None, read_view_file(uri, "se/rd/serde").unwrap());
+        assert_eq!(None, read_view_file(dir.join("missing").to_str().unwrap(), "config.json").unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
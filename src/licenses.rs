@@ -0,0 +1,337 @@
+use flate2::read::GzDecoder;
+use super::config;
+use glob::{MatchOptions, Pattern};
+use reloadable::ReloadableFile;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tar::Archive;
+use toml;
+
+// Crate name & version
+type VersionKey = (String, String);
+
+// License expression of each checked version, or why it couldn't be read
+type Licenses = HashMap<VersionKey, Result<Option<String>, String>>;
+
+// Marks the versions whose license couldn't be read in the licenses file, it isn't a valid expression
+const UNREADABLE : &str = "!";
+
+/// SPDX license expression, e.g. `MIT OR Apache-2.0`
+#[derive(Debug, PartialEq)]
+enum LicenseExpr {
+    License(String),
+    And(Vec<LicenseExpr>),
+    Or(Vec<LicenseExpr>),
+}
+
+fn parse_or(tokens: &[String], pos: &mut usize) -> Result<LicenseExpr, String> {
+    let mut terms = vec![parse_and(tokens, pos)?];
+    while tokens.get(*pos).map(|token| token.eq_ignore_ascii_case("OR")).unwrap_or(false) {
+        *pos += 1;
+        terms.push(parse_and(tokens, pos)?);
+    }
+    Ok(if terms.len() == 1 { terms.remove(0) } else { LicenseExpr::Or(terms) })
+}
+
+fn parse_and(tokens: &[String], pos: &mut usize) -> Result<LicenseExpr, String> {
+    let mut terms = vec![parse_term(tokens, pos)?];
+    while tokens.get(*pos).map(|token| token.eq_ignore_ascii_case("AND")).unwrap_or(false) {
+        *pos += 1;
+        terms.push(parse_term(tokens, pos)?);
+    }
+    Ok(if terms.len() == 1 { terms.remove(0) } else { LicenseExpr::And(terms) })
+}
+
+fn parse_term(tokens: &[String], pos: &mut usize) -> Result<LicenseExpr, String> {
+    let token = tokens.get(*pos).ok_or_else(|| String::from("Unexpected end of license expression"))?;
+    *pos += 1;
+    if token == "(" {
+        let expr = parse_or(tokens, pos)?;
+        if tokens.get(*pos).map(String::as_str) != Some(")") {
+            return Err(String::from("Unbalanced parentheses in license expression"));
+        }
+        *pos += 1;
+        return Ok(expr);
+    }
+    if token == ")" || ["AND", "OR", "WITH"].iter().any(|operator| token.eq_ignore_ascii_case(operator)) {
+        return Err(format!("Unexpected {:?} in license expression", token));
+    }
+    // An exception only widens the license, so the license alone decides
+    if tokens.get(*pos).map(|token| token.eq_ignore_ascii_case("WITH")).unwrap_or(false) {
+        match tokens.get(*pos + 1) {
+            Some(exception) if exception != "(" && exception != ")" => *pos += 2,
+            _ => return Err(String::from("Missing exception after WITH")),
+        }
+    }
+    Ok(LicenseExpr::License(token.trim_end_matches('+').to_string()))
+}
+
+impl LicenseExpr {
+    /// Parses an expression, `/` being the legacy separator of alternative licenses on crates.io
+    fn parse(expression: &str) -> Result<LicenseExpr, String> {
+        let tokens = expression.replace('/', " OR ").replace('(', " ( ").replace(')', " ) ")
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let mut pos = 0;
+        let expr = parse_or(&tokens, &mut pos)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(format!("Unexpected {:?} in license expression", token));
+        }
+        Ok(expr)
+    }
+
+    fn satisfies<F>(&self, allows: &F) -> bool where F: Fn(&str) -> bool {
+        match self {
+            LicenseExpr::License(license) => allows(license),
+            LicenseExpr::And(terms) => terms.iter().all(|term| term.satisfies(allows)),
+            LicenseExpr::Or(terms) => terms.iter().any(|term| term.satisfies(allows)),
+        }
+    }
+}
+
+/// Allowed & denied licenses, as glob patterns of SPDX identifiers
+pub struct LicensePolicy {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl LicensePolicy {
+    /// The patterns were validated when the configuration was loaded
+    pub fn new(licenses: &config::Licenses) -> LicensePolicy {
+        let patterns = |patterns: &Vec<String>| patterns.iter()
+            .filter_map(|pattern| Pattern::new(pattern).ok())
+            .collect();
+        LicensePolicy { allow: patterns(&licenses.allow), deny: patterns(&licenses.deny) }
+    }
+
+    fn allows_license(&self, license: &str) -> bool {
+        let options = MatchOptions { case_sensitive: false, require_literal_separator: false, require_literal_leading_dot: false };
+        let matches = |patterns: &Vec<Pattern>| patterns.iter().any(|pattern| pattern.matches_with(license, &options));
+        !matches(&self.deny) && (self.allow.is_empty() || matches(&self.allow))
+    }
+
+    /// Whether a license expression can be satisfied by the allowed licenses.
+    /// Crates without a license expression are only allowed when no allowed licenses are configured.
+    pub fn allows(&self, expression: Option<&str>) -> bool {
+        match expression {
+            Some(expression) => LicenseExpr::parse(expression)
+                .map(|expr| expr.satisfies(&|license: &str| self.allows_license(license)))
+                .unwrap_or(false),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(alias = "project")]
+    package: ManifestPackage,
+}
+
+#[derive(Deserialize)]
+struct ManifestPackage {
+    license: Option<String>,
+}
+
+/// Reads the license expression from the `Cargo.toml` of a `.crate` tarball
+pub fn read_crate_license(crate_file: &Path, name: &str, version: &str) -> io::Result<Option<String>> {
+    let manifest_path = PathBuf::from(format!("{}-{}", name, version)).join("Cargo.toml");
+    let mut archive = Archive::new(GzDecoder::new(File::open(crate_file)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == manifest_path {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            return toml::from_str::<Manifest>(&contents)
+                .map(|manifest| manifest.package.license)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("No {:?} in {:?}", manifest_path, crate_file)))
+}
+
+/// Location of the licenses of the downloaded versions of a registry, next to its git metadata
+pub fn licenses_file(registry_uri: &str) -> PathBuf {
+    Path::new(registry_uri).join(".git").join("mirage-licenses")
+}
+
+/// Reads `name version expression` lines, the expression is empty for versions without a license,
+/// and is `! error` for versions whose license couldn't be read
+fn read_licenses(path: &Path) -> io::Result<Licenses> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut licenses = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.trim().splitn(3, ' ');
+        match (fields.next(), fields.next()) {
+            (Some(name), Some(vers)) => {
+                let license = match fields.next().map(str::trim).filter(|license| !license.is_empty()) {
+                    Some(error) if error.starts_with(UNREADABLE) => Err(error[UNREADABLE.len()..].trim().to_string()),
+                    license => Ok(license.map(String::from)),
+                };
+                licenses.insert((String::from(name), String::from(vers)), license);
+            },
+            (Some(""), None) => (),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid license entry: {}", line))),
+        }
+    }
+    Ok(licenses)
+}
+
+/// Licenses of the downloaded versions, checked against the policy of the registry.
+/// The policy applies to the recorded licenses, so policy changes don't require downloading the crates again.
+/// Versions whose license couldn't be read are denied whatever the policy.
+pub struct LicenseList {
+    policy: LicensePolicy,
+    licenses: ReloadableFile<Licenses>,
+    appending: Mutex<()>,
+}
+
+impl LicenseList {
+    pub fn new(registry_uri: &str, licenses: &config::Licenses) -> LicenseList {
+        LicenseList {
+            policy: LicensePolicy::new(licenses),
+            licenses: ReloadableFile::new(licenses_file(registry_uri), read_licenses),
+            appending: Mutex::new(()),
+        }
+    }
+
+    pub fn reload(&self) {
+        self.licenses.reload_if_modified();
+    }
+
+    fn license(&self, name: &str, version: &str) -> Option<Result<Option<String>, String>> {
+        self.licenses.with(|licenses| licenses.get(&(String::from(name), String::from(version))).cloned())
+    }
+
+    pub fn is_checked(&self, name: &str, version: &str) -> bool {
+        self.license(name, version).is_some()
+    }
+
+    fn allows(&self, license: &Result<Option<String>, String>) -> bool {
+        license.as_ref().map(|license| self.policy.allows(license.as_deref())).unwrap_or(false)
+    }
+
+    /// Whether the recorded license of a version is denied, versions which weren't checked yet aren't
+    pub fn is_denied(&self, name: &str, version: &str) -> bool {
+        self.license(name, version)
+            .map(|license| !self.allows(&license))
+            .unwrap_or(false)
+    }
+
+    /// Appends the license of a version, the crawlers record their downloads concurrently
+    pub fn record(&self, name: &str, version: &str, license: Option<&str>) -> io::Result<()> {
+        self.append(name, version, Ok(license.map(String::from)))
+    }
+
+    /// Appends a version whose license couldn't be read, so that it's denied rather than served unchecked
+    pub fn record_unreadable(&self, name: &str, version: &str, error: &str) -> io::Result<()> {
+        self.append(name, version, Err(error.split_whitespace().collect::<Vec<_>>().join(" ")))
+    }
+
+    fn append(&self, name: &str, version: &str, license: Result<Option<String>, String>) -> io::Result<()> {
+        let _appending = self.appending.lock().map_err(|_| io::Error::other("License recording poisoned"))?;
+        let mut file = OpenOptions::new().append(true).create(true).open(self.licenses.path())?;
+        match license {
+            Ok(ref license) => writeln!(file, "{} {} {}", name, version, license.as_deref().unwrap_or_default())?,
+            Err(ref error) => writeln!(file, "{} {} {} {}", name, version, UNREADABLE, error)?,
+        }
+        // Appends within the resolution of the modification time aren't noticed by a reload
+        self.licenses.update(|licenses| {
+            licenses.insert((String::from(name), String::from(version)), license);
+        });
+        Ok(())
+    }
+
+    /// Versions with a denied license, with their license expression or why it couldn't be read
+    pub fn denied(&self) -> Vec<(String, String, Result<Option<String>, String>)> {
+        let mut denied = self.licenses.with(|licenses| licenses.iter()
+            .filter(|(_, license)| !self.allows(license))
+            .map(|((name, vers), license)| (name.clone(), vers.clone(), license.clone()))
+            .collect::<Vec<_>>());
+        denied.sort();
+        denied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use tar::{Builder, Header};
+
+    #[test]
+    fn evaluate_license_expressions() {
+        let policy = LicensePolicy::new(&config::Licenses{ allow: vec![], deny: vec![String::from("AGPL-*")] });
+        assert!(policy.allows(Some("MIT OR Apache-2.0")));
+        assert!(policy.allows(Some("MIT/AGPL-3.0")));
+        assert!(!policy.allows(Some("agpl-3.0-only")));
+        assert!(!policy.allows(Some("MIT AND AGPL-3.0-or-later")));
+        assert!(policy.allows(Some("(MIT AND Unicode-DFS-2016) OR (GPL-2.0+ WITH Classpath-exception-2.0)")));
+        assert!(!policy.allows(Some("MIT AND (")));
+        assert!(!policy.allows(Some("MIT WITH")));
+        assert!(!policy.allows(Some("(MIT")));
+        assert!(!policy.allows(Some("MIT)")));
+        assert_eq!(Err(String::from("Missing exception after WITH")), LicenseExpr::parse("MIT WITH"));
+        assert_eq!(Err(String::from("Unbalanced parentheses in license expression")), LicenseExpr::parse("(MIT"));
+        assert!(policy.allows(None));
+
+        let allowlist = LicensePolicy::new(&config::Licenses{ allow: vec![String::from("MIT"), String::from("Apache-2.0")], deny: vec![] });
+        assert!(allowlist.allows(Some("Apache-2.0 WITH LLVM-exception")));
+        assert!(!allowlist.allows(Some("MIT AND BSD-3-Clause")));
+        assert!(!allowlist.allows(None));
+    }
+
+    #[test]
+    fn record_crate_licenses() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-licenses-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let crate_file = dir.join("evil-0.1.0.crate");
+        {
+            let manifest = b"[package]\nname = \"evil\"\nversion = \"0.1.0\"\nlicense = \"AGPL-3.0\"\n";
+            let mut header = Header::new_gnu();
+            header.set_size(manifest.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            let mut builder = Builder::new(GzEncoder::new(File::create(&crate_file).unwrap(), Compression::default()));
+            builder.append_data(&mut header, "evil-0.1.0/Cargo.toml", &manifest[..]).unwrap();
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        let license = read_crate_license(&crate_file, "evil", "0.1.0").unwrap();
+        assert_eq!(Some(String::from("AGPL-3.0")), license);
+
+        let uri = dir.to_str().unwrap();
+        let licenses = LicenseList::new(uri, &config::Licenses{ allow: vec![], deny: vec![String::from("AGPL-*")] });
+        licenses.record("evil", "0.1.0", license.as_deref()).unwrap();
+        licenses.record("serde", "1.0.0", Some("MIT OR Apache-2.0")).unwrap();
+        licenses.record("unlicensed", "0.1.0", None).unwrap();
+        assert!(licenses.is_denied("evil", "0.1.0"));
+        assert!(!licenses.is_denied("serde", "1.0.0"));
+        assert!(licenses.is_checked("unlicensed", "0.1.0"));
+        assert!(!licenses.is_checked("serde", "2.0.0"));
+        assert_eq!(vec![(String::from("evil"), String::from("0.1.0"), Ok(Some(String::from("AGPL-3.0"))))], licenses.denied());
+
+        fs::write(&crate_file, "not a tarball").unwrap();
+        let error = read_crate_license(&crate_file, "evil", "0.1.1").unwrap_err();
+        licenses.record_unreadable("evil", "0.1.1", &format!("Invalid\n{}", error)).unwrap();
+        assert!(licenses.is_checked("evil", "0.1.1") && licenses.is_denied("evil", "0.1.1"));
+        let reloaded = read_licenses(&licenses_file(uri)).unwrap();
+        assert_eq!(Some(&Err(format!("Invalid {}", error))), reloaded.get(&(String::from("evil"), String::from("0.1.1"))));
+        assert_eq!(Some(&Ok(None)), reloaded.get(&(String::from("unlicensed"), String::from("0.1.0"))));
+        assert_eq!(2, licenses.denied().len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
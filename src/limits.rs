@@ -0,0 +1,155 @@
+use actix_web::{HttpRequest, HttpResponse, Result, http::{header, StatusCode}};
+use actix_web::middleware::{Middleware, Started, Finished};
+use super::config;
+use auth::AuthenticatedToken;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Idle clients are forgotten once this many are being tracked
+const MAX_TRACKED_CLIENTS : usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: u32,
+}
+
+impl Bucket {
+    fn new(limit: &config::Limit, now: Instant) -> Bucket {
+        Bucket { tokens: f64::from(limit.burst), last_refill: now, in_flight: 0 }
+    }
+
+    fn refill(&mut self, limit: &config::Limit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed_secs * limit.rate).min(f64::from(limit.burst));
+        self.last_refill = now;
+    }
+
+    fn is_idle(&self, limit: &config::Limit) -> bool {
+        self.in_flight == 0 && self.tokens >= f64::from(limit.burst)
+    }
+
+    /// Takes a token for a new request, or returns how long the client should wait
+    fn try_acquire(&mut self, limit: &config::Limit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if limit.concurrent.map(|concurrent| self.in_flight >= concurrent).unwrap_or(false) {
+            return Err(Duration::from_secs(1));
+        }
+        if self.tokens < 1.0 {
+            let wait_secs = ((1.0 - self.tokens) / limit.rate).ceil().max(1.0);
+            return Err(Duration::from_secs(wait_secs as u64));
+        }
+        self.tokens -= 1.0;
+        self.in_flight += 1;
+        Ok(())
+    }
+}
+
+/// Token buckets per client, shared by all server workers
+pub struct RateLimiter {
+    limit: config::Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &config::Limit) -> RateLimiter {
+        RateLimiter { limit: limit.clone(), buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn acquire(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned");
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let limit = &self.limit;
+            buckets.retain(|_, bucket| {
+                bucket.refill(limit, now);
+                !bucket.is_idle(limit)
+            });
+        }
+        buckets.entry(String::from(client))
+            .or_insert_with(|| Bucket::new(&self.limit, now))
+            .try_acquire(&self.limit, now)
+    }
+
+    fn release(&self, client: &str) {
+        let mut buckets = self.buckets.lock().expect("Rate limiter lock poisoned");
+        if let Some(bucket) = buckets.get_mut(client) {
+            bucket.in_flight = bucket.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+// Client which was admitted by the rate limiter, released once the response is sent
+struct AdmittedClient(String);
+
+/// Middleware answering 429 to clients exceeding their rate or concurrency limit.
+/// Clients are identified by their auth token when present, by their IP address otherwise.
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> RateLimit {
+        RateLimit { limiter }
+    }
+}
+
+fn client_key<S>(req: &HttpRequest<S>) -> String {
+    match req.extensions().get::<AuthenticatedToken>() {
+        Some(AuthenticatedToken(name)) => format!("token:{}", name),
+        None => req.peer_addr()
+            .map(|addr| format!("ip:{}", addr.ip()))
+            .unwrap_or_else(|| String::from("unknown")),
+    }
+}
+
+impl<S> Middleware<S> for RateLimit {
+    fn start(&self, req: &mut HttpRequest<S>) -> Result<Started> {
+        let client = client_key(req);
+        match self.limiter.acquire(&client, Instant::now()) {
+            Ok(()) => {
+                req.extensions_mut().insert(AdmittedClient(client));
+                Ok(Started::Done)
+            },
+            Err(retry_after) => Ok(Started::Response(HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+                .header(header::RETRY_AFTER, retry_after.as_secs().to_string())
+                .finish())),
+        }
+    }
+
+    fn finish(&self, req: &mut HttpRequest<S>, _resp: &HttpResponse) -> Finished {
+        if let Some(AdmittedClient(client)) = req.extensions_mut().remove::<AdmittedClient>() {
+            self.limiter.release(&client);
+        }
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn limit_request_rate() {
+        let limiter = RateLimiter::new(&config::Limit{ rate: 0.5, burst: 2, concurrent: None });
+        let now = Instant::now();
+        assert_eq!(Ok(()), limiter.acquire("ip:10.0.0.1", now));
+        assert_eq!(Ok(()), limiter.acquire("ip:10.0.0.1", now));
+        assert_eq!(Err(Duration::from_secs(2)), limiter.acquire("ip:10.0.0.1", now));
+        assert_eq!(Ok(()), limiter.acquire("ip:10.0.0.2", now));
+        assert_eq!(Ok(()), limiter.acquire("ip:10.0.0.1", now + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn limit_concurrent_requests() {
+        let limiter = RateLimiter::new(&config::Limit{ rate: 100.0, burst: 100, concurrent: Some(1) });
+        let now = Instant::now();
+        assert_eq!(Ok(()), limiter.acquire("token:ci", now));
+        assert_eq!(Err(Duration::from_secs(1)), limiter.acquire("token:ci", now));
+        limiter.release("token:ci");
+        assert_eq!(Ok(()), limiter.acquire("token:ci", now));
+    }
+}
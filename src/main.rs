@@ -1,6 +1,7 @@
 extern crate actix;
 extern crate actix_web;
 extern crate futures;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate toml;
@@ -10,13 +11,41 @@ extern crate reqwest;
 extern crate futures_cpupool;
 extern crate glob;
 extern crate serde_json;
+extern crate sha2;
+extern crate hex;
+extern crate rand;
+extern crate semver;
+extern crate flate2;
+extern crate tar;
+extern crate tokio_uds;
+extern crate url;
 
+mod advisories;
+mod approval;
+mod auth;
+mod closure;
 mod config;
 mod crate_store;
 mod crate_registry;
+mod denylist;
 mod git_utils;
+mod index_entry;
+mod index_view;
+mod licenses;
+mod limits;
+mod proxy;
+mod quarantine;
+mod reloadable;
+mod schedule;
+mod snapshots;
+mod supervisor;
+mod throttle;
 
-use clap::Arg;
+use clap::{Arg, SubCommand};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use supervisor::Supervisor;
 
 fn parse_command_args() -> clap::ArgMatches<'static> {
     clap::App::new("Cargo mirror")
@@ -33,9 +62,119 @@ fn parse_command_args() -> clap::ArgMatches<'static> {
             .short("v")
             .multiple(true)
             .help("Sets the level of verbosity"))
+        .subcommand(SubCommand::with_name("token")
+            .about("Manages the tokens for authenticated access to the mirror")
+            .subcommand(SubCommand::with_name("add")
+                .about("Creates a new token")
+                .arg(Arg::with_name("name").required(true).help("Name of the token")))
+            .subcommand(SubCommand::with_name("revoke")
+                .about("Revokes an existing token")
+                .arg(Arg::with_name("name").required(true).help("Name of the token")))
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists the names of all tokens")))
+        .subcommand(SubCommand::with_name("sync")
+            .about("Mirrors the dependency closure of the given crates only")
+            .arg(Arg::with_name("registry")
+                .short("r")
+                .long("registry")
+                .value_name("NAME")
+                .help("Name of the registry, defaults to the first one")
+                .takes_value(true))
+            .arg(Arg::with_name("crate")
+                .long("crate")
+                .value_name("NAME[@REQ]")
+                .help("Root crate of the closure, e.g. tokio@1 or serde for all versions")
+                .required(true)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("crates")
+            .about("Approves or rejects crates which were never mirrored before")
+            .arg(Arg::with_name("registry")
+                .short("r")
+                .long("registry")
+                .value_name("NAME")
+                .help("Name of the registry, defaults to the first one")
+                .takes_value(true))
+            .subcommand(SubCommand::with_name("pending")
+                .about("Lists the crates awaiting approval"))
+            .subcommand(SubCommand::with_name("approve")
+                .about("Mirrors the crates from now on")
+                .arg(Arg::with_name("name").required(true).multiple(true).help("Name of the crate")))
+            .subcommand(SubCommand::with_name("reject")
+                .about("Never mirrors the crates")
+                .arg(Arg::with_name("name").required(true).multiple(true).help("Name of the crate"))))
         .get_matches()
 }
 
+fn manage_tokens(config: &config::Configuration, cmd_args: &clap::ArgMatches) {
+    let token_file = &config.crate_store.auth.as_ref()
+        .expect("No token file configured in [crate_store.auth]")
+        .token_file;
+    match cmd_args.subcommand() {
+        ("add", Some(args)) => {
+            let name = args.value_of("name").unwrap_or_default();
+            let token = auth::add_token(token_file, name)
+                .unwrap_or_else(|e| panic!("Could not add token {}: {:?}", name, e));
+            println!("Added token {}. It will not be shown again:\n{}", name, token);
+        },
+        ("revoke", Some(args)) => {
+            let name = args.value_of("name").unwrap_or_default();
+            let revoked = auth::revoke_token(token_file, name)
+                .unwrap_or_else(|e| panic!("Could not revoke token {}: {:?}", name, e));
+            if revoked {
+                println!("Revoked token {}", name);
+            } else {
+                eprintln!("Unknown token {}", name);
+            }
+        },
+        _ => auth::list_tokens(token_file)
+            .unwrap_or_else(|e| panic!("Could not read token file {}: {:?}", token_file, e))
+            .iter()
+            .for_each(|name| println!("{}", name)),
+    }
+}
+
+fn find_registry<'a>(config: &'a config::Configuration, cmd_args: &clap::ArgMatches) -> &'a config::CrateRegistry {
+    match cmd_args.value_of("registry") {
+        Some(name) => config.crate_registry.iter().find(|registry| registry.name == name),
+        None => config.crate_registry.first(),
+    }.expect("Unknown registry")
+}
+
+fn manage_crates(config: &config::Configuration, cmd_args: &clap::ArgMatches) {
+    let registry = find_registry(config, cmd_args);
+    let names = |args: &clap::ArgMatches| args.values_of("name")
+        .map(|names| names.map(String::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+    match cmd_args.subcommand() {
+        ("approve", Some(args)) => approval::decide(&registry.uri, &names(args), approval::Approval::Approved)
+            .unwrap_or_else(|e| panic!("Could not approve crates: {:?}", e)),
+        ("reject", Some(args)) => approval::decide(&registry.uri, &names(args), approval::Approval::Rejected)
+            .unwrap_or_else(|e| panic!("Could not reject crates: {:?}", e)),
+        _ => approval::pending(&registry.uri)
+            .unwrap_or_else(|e| panic!("Could not read approvals of {}: {:?}", registry.name, e))
+            .iter()
+            .for_each(|name| println!("{}", name)),
+    }
+}
+
+fn sync_crates(config: &config::Configuration, cmd_args: &clap::ArgMatches) {
+    if config.crate_store.offline {
+        panic!("Can not sync crates from upstream in offline mode");
+    }
+    let registry = find_registry(config, cmd_args);
+    let roots = cmd_args.values_of("crate")
+        .map(|specs| specs.map(|spec| closure::parse_root(spec).unwrap_or_else(|e| panic!("{}", e))).collect::<Vec<_>>())
+        .unwrap_or_default();
+    crate_registry::update(registry, &config.crate_store, &config.upstream.http);
+    let versions = closure::resolve(&registry.uri, &roots, &registry.closure)
+        .unwrap_or_else(|e| panic!("Could not resolve the dependencies of {:?}: {:?}", roots, e));
+    let crates = versions.iter().map(|version| version.name.to_lowercase()).collect::<BTreeSet<_>>();
+    println!("Resolved {} versions of {} crates", versions.len(), crates.len());
+    crate_store::download_versions(&config.crate_store, &config.upstream.http, registry, versions);
+}
+
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
     let cmd_args = parse_command_args();
@@ -45,12 +184,38 @@ fn main() {
                 println!("Using default configuration");
                 config::Configuration::default()
             }, config::parse_config);
-    
+
+    if let Some(token_args) = cmd_args.subcommand_matches("token") {
+        return manage_tokens(&config, token_args);
+    }
+    if let Some(crates_args) = cmd_args.subcommand_matches("crates") {
+        return manage_crates(&config, crates_args);
+    }
+    // The certificate authorities of libgit2 are shared by all its connections, whichever registry configures them
+    let ca_bundles = config.upstream.http.ca_certificates.iter()
+        .chain(config.crate_registry.iter().filter_map(|registry| registry.git.ca_bundle.as_ref()))
+        .collect::<BTreeSet<_>>();
+    for ca_bundle in ca_bundles {
+        git_utils::trust_ca_bundle(ca_bundle)
+            .unwrap_or_else(|e| panic!("Could not load CA certificate {}: {}", ca_bundle, e.message()));
+    }
+    if let Some(sync_args) = cmd_args.subcommand_matches("sync") {
+        return sync_crates(&config, sync_args);
+    }
+
     let sys = actix::System::new("Crates mirror");
 
-    let (stop_crate_registry, start_crate_download) = crate_registry::start(&config.crate_registry, &config.crate_store);
-    crate_store::start(&config.crate_store, &config.crate_registry.uri, start_crate_download);
+    let supervisor = Arc::new(Supervisor::new());
+    let registries = config.crate_registry.iter()
+        .map(|registry_config| {
+            let (start_crate_download, sync_trigger) = crate_registry::start(registry_config, &config.crate_store, &config.upstream.http, &supervisor);
+            (registry_config.clone(), start_crate_download, sync_trigger)
+        })
+        .collect();
+    crate_store::start(&config.crate_store, &config.upstream.http, registries, &supervisor);
 
+    // The server stops on SIGINT & SIGTERM, the latter after the requests in flight are done
     let _ = sys.run();
-    stop_crate_registry.send(()).expect("Could not stop registry monitoring thread");
+    println!("Waiting for the background tasks & downloads to finish");
+    supervisor.shutdown(Duration::from_secs(u64::from(config.crate_store.shutdown_timeout())));
 }
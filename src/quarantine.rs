@@ -0,0 +1,218 @@
+use git2::{Repository, Commit, Error, Oid, Sort};
+use super::config;
+use glob::Pattern;
+use index_entry::IndexEntry;
+use reloadable::{self, ReloadableFile};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Crate name & version
+type VersionKey = (String, String);
+
+fn parse_index_line(line: &[u8]) -> Option<VersionKey> {
+    str::from_utf8(line).ok()
+        .and_then(|line| IndexEntry::parse(line).ok())
+        .map(|entry| (entry.name, entry.vers))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_secs()).unwrap_or(0)
+}
+
+/// Location of the first-seen times of the versions of a registry, next to its git metadata
+pub fn first_seen_file(registry_uri: &str) -> PathBuf {
+    Path::new(registry_uri).join(".git").join("mirage-first-seen")
+}
+
+/// First-seen times of the versions published within the quarantine,
+/// and the last commit whose history was walked to find them
+#[derive(Debug, Default, PartialEq)]
+struct FirstSeen {
+    walked: Option<Oid>,
+    versions: HashMap<VersionKey, u64>,
+}
+
+/// Reads the first line as the walked commit, then `name version seconds` lines. A missing file has no versions.
+fn read_first_seen(path: &Path) -> io::Result<FirstSeen> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(FirstSeen::default()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let walked = match lines.next() {
+        Some(line) => Oid::from_str(line?.trim()).ok(),
+        None => None,
+    };
+    let mut versions = HashMap::new();
+    for line in lines {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next().and_then(|secs| secs.parse::<u64>().ok())) {
+            (Some(name), Some(vers), Some(secs)) => {
+                versions.insert((String::from(name), String::from(vers)), secs);
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid first-seen entry: {}", line))),
+        }
+    }
+    Ok(FirstSeen { walked, versions })
+}
+
+fn read_first_seen_versions(path: &Path) -> io::Result<HashMap<VersionKey, u64>> {
+    read_first_seen(path).map(|first_seen| first_seen.versions)
+}
+
+fn write_first_seen(file: &mut File, first_seen: &FirstSeen) -> io::Result<()> {
+    writeln!(file, "{}", first_seen.walked.map(|oid| oid.to_string()).unwrap_or_default())?;
+    first_seen.versions.iter()
+        .try_for_each(|((name, vers), secs)| writeln!(file, "{} {} {}", name, vers, secs))
+}
+
+/// Versions whose index line was added by a commit, compared to its parent.
+/// Updated lines, e.g. of yanked versions, don't count as added.
+fn added_versions(repo: &Repository, parent: &Commit, commit: &Commit) -> Result<Vec<VersionKey>, Error> {
+    let diff = repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&commit.tree()?), None)?;
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    diff.foreach(&mut |_, _| true, None, None, Some(&mut |_, _, line| {
+        match line.origin() {
+            '+' => added.extend(parse_index_line(line.content())),
+            '-' => removed.extend(parse_index_line(line.content())),
+            _ => (),
+        }
+        true
+    }))?;
+    Ok(added.into_iter().filter(|version| !removed.contains(version)).collect())
+}
+
+/// Walks the commits since the previous walk, and records the versions whose index line they added as seen `now`.
+/// Commit times only tell when a version was published upstream, not when the mirror first saw it.
+/// Without a previous walk, the walk stops at the commits older than the quarantine.
+/// Root & merge commits are skipped, as they only repeat versions published by other commits,
+/// e.g. when the upstream history was squashed.
+pub fn record_first_seen(repo: &Repository, registry_uri: &str, quarantine: &config::Quarantine, now: SystemTime) -> Result<(), Error> {
+    let path = first_seen_file(registry_uri);
+    let mut first_seen = read_first_seen(&path)
+        .map_err(|e| Error::from_str(format!("Could not read {:?}: {:?}", path, e).as_str()))?;
+    let seen = unix_secs(now);
+    let quarantine_start = seen.saturating_sub(u64::from(quarantine.hours) * 3600);
+
+    let head = repo.head()?.peel_to_commit()?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TIME);
+    revwalk.push(head.id())?;
+    // The previous walk may be gone, when the local history was rebuilt
+    let continued = first_seen.walked.map(|walked| revwalk.hide(walked).is_ok()).unwrap_or(false);
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if !continued && (commit.time().seconds().max(0) as u64) < quarantine_start {
+            break;
+        }
+        let parents = commit.parents().collect::<Vec<_>>();
+        if parents.len() != 1 {
+            continue;
+        }
+        for version in added_versions(repo, &parents[0], &commit)? {
+            first_seen.versions.entry(version).or_insert(seen);
+        }
+    }
+    first_seen.versions.retain(|_, seen| *seen >= quarantine_start);
+    first_seen.walked = Some(head.id());
+
+    reloadable::write_atomically(&path, |file| write_first_seen(file, &first_seen))
+        .map_err(|e| Error::from_str(format!("Could not write {:?}: {:?}", path, e).as_str()))
+}
+
+/// Versions in quarantine, reloaded whenever the registry monitor records new first-seen times
+pub struct QuarantineList {
+    hours: u32,
+    exempt: Vec<Pattern>,
+    first_seen: ReloadableFile<HashMap<VersionKey, u64>>,
+}
+
+impl QuarantineList {
+    pub fn new(registry_uri: &str, quarantine: &config::Quarantine) -> QuarantineList {
+        QuarantineList {
+            hours: quarantine.hours,
+            exempt: quarantine.exempt.iter().filter_map(|pattern| Pattern::new(pattern).ok()).collect(),
+            first_seen: ReloadableFile::new(first_seen_file(registry_uri), read_first_seen_versions),
+        }
+    }
+
+    pub fn reload(&self) {
+        self.first_seen.reload_if_modified();
+    }
+
+    pub fn is_quarantined(&self, name: &str, version: &str, now: SystemTime) -> bool {
+        if self.exempt.iter().any(|pattern| pattern.matches(name)) {
+            return false;
+        }
+        let quarantine_start = unix_secs(now).saturating_sub(u64::from(self.hours) * 3600);
+        self.first_seen.with(|first_seen| first_seen.get(&(String::from(name), String::from(version))).cloned())
+            .map(|seen| seen >= quarantine_start)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use git2::Signature;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::Duration;
+
+    const SERDE_1 : &str = r#"{"name":"serde","vers":"1.0.0","deps":[],"cksum":"aa","features":{},"yanked":false}"#;
+    const SERDE_2 : &str = r#"{"name":"serde","vers":"2.0.0","deps":[],"cksum":"bb","features":{},"yanked":false}"#;
+    const SERDE_3 : &str = r#"{"name":"serde","vers":"3.0.0","deps":[],"cksum":"cc","features":{},"yanked":false}"#;
+
+    #[test]
+    fn quarantine_recent_versions() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-quarantine-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let commit = |contents: &str, secs: i64| {
+            let signature = Signature::new("upstream", "upstream@crates.io", &git2::Time::new(secs, 0)).unwrap();
+            let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+            let mut tree_builder = repo.treebuilder(parent.as_ref().map(|parent| parent.tree().unwrap()).as_ref()).unwrap();
+            tree_builder.insert("serde", repo.blob(contents.as_bytes()).unwrap(), 0o100_644).unwrap();
+            let tree = repo.find_tree(tree_builder.write().unwrap()).unwrap();
+            let parents = parent.iter().collect::<Vec<_>>();
+            repo.commit(Some("HEAD"), &signature, &signature, "update", &tree, &parents).unwrap();
+        };
+        let hour = Duration::from_secs(3600);
+        let now = UNIX_EPOCH + hour * 1000;
+        let uri = dir.to_str().unwrap();
+        let quarantine = config::Quarantine{ hours: 24, exempt: vec![] };
+
+        commit(SERDE_1, 900 * 3600);
+        commit(&SERDE_1.replace("false", "true"), 980 * 3600);
+        commit(&format!("{}\n{}\n", SERDE_1, SERDE_2), 990 * 3600);
+        record_first_seen(&repo, uri, &quarantine, now).unwrap();
+
+        let quarantined = QuarantineList::new(uri, &quarantine);
+        assert!(!quarantined.is_quarantined("serde", "2.0.0", now));
+        quarantined.reload();
+        assert!(!quarantined.is_quarantined("serde", "1.0.0", now));
+        assert!(quarantined.is_quarantined("serde", "2.0.0", now));
+        assert!(quarantined.is_quarantined("serde", "2.0.0", now + hour * 23));
+        assert!(!quarantined.is_quarantined("serde", "2.0.0", now + hour * 25));
+
+        // A version committed long ago, which reaches the mirror only now, is quarantined from now on
+        commit(&format!("{}\n{}\n{}\n", SERDE_1, SERDE_2, SERDE_3), 500 * 3600);
+        record_first_seen(&repo, uri, &quarantine, now + hour * 30).unwrap();
+        quarantined.reload();
+        assert!(quarantined.is_quarantined("serde", "3.0.0", now + hour * 30));
+        assert!(!quarantined.is_quarantined("serde", "2.0.0", now + hour * 30));
+
+        let exempted = QuarantineList::new(uri, &config::Quarantine{ hours: 24, exempt: vec![String::from("ser*")] });
+        assert!(!exempted.is_quarantined("serde", "2.0.0", now));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
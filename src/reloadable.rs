@@ -0,0 +1,99 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+use std::time::SystemTime;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Writes to a temporary file first, so the crate store never reads a partial file.
+/// The temporary file is removed when the write fails.
+pub fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()> {
+    write_through_partial_file(path, OpenOptions::new().write(true).create(true).truncate(true), write)
+}
+
+/// Writes atomically a file only readable by its owner, e.g. with credentials
+pub fn write_private_atomically<F>(path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()> {
+    write_through_partial_file(path, OpenOptions::new().write(true).create(true).truncate(true).mode(0o600), write)
+}
+
+fn write_through_partial_file<F>(path: &Path, options: &OpenOptions, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()> {
+    let partial_path = path.with_extension("partial");
+    let written = options.open(&partial_path)
+        .and_then(|mut file| write(&mut file));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    fs::rename(partial_path, path)
+}
+
+/// Locks a file against concurrent updates, by the other threads as by the command line, until the lock is dropped.
+/// The lock is taken on a `.lock` file next to it, as the file itself is replaced when written.
+pub fn lock_for_update(path: &Path) -> io::Result<File> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock = OpenOptions::new().write(true).create(true).truncate(false).open(lock_path)?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Contents of a file shared by the server workers & the crawlers, reloaded when the file was modified since it was read.
+/// The file is only reloaded on request, so that checking many versions doesn't stat the file for each of them.
+pub struct ReloadableFile<T> {
+    path: PathBuf,
+    read: fn(&Path) -> io::Result<T>,
+    contents: RwLock<(Option<SystemTime>, T)>,
+}
+
+impl<T: Default> ReloadableFile<T> {
+    pub fn new(path: PathBuf, read: fn(&Path) -> io::Result<T>) -> ReloadableFile<T> {
+        ReloadableFile {
+            path,
+            read,
+            contents: RwLock::new((None, T::default())),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the previous contents when the file can't be read
+    pub fn reload_if_modified(&self) {
+        let modified = modified(&self.path);
+        let outdated = self.contents.read().map(|contents| contents.0 != modified).unwrap_or(true);
+        if outdated {
+            match (self.read)(&self.path) {
+                Ok(contents) => *self.contents.write().unwrap_or_else(PoisonError::into_inner) = (modified, contents),
+                Err(e) => eprintln!("Could not read {:?}: {:?}", self.path, e),
+            }
+        }
+    }
+
+    /// Contents as of the last reload
+    pub fn with<F, R>(&self, f: F) -> R where F: FnOnce(&T) -> R {
+        f(&self.contents.read().unwrap_or_else(PoisonError::into_inner).1)
+    }
+
+    /// Updates the contents in memory, after the file was appended to
+    pub fn update<F>(&self, f: F) where F: FnOnce(&mut T) {
+        f(&mut self.contents.write().unwrap_or_else(PoisonError::into_inner).1)
+    }
+
+    /// Writes new contents to the file with `write`. Writes within the resolution
+    /// of the modification time wouldn't be noticed by a reload, so the contents are kept in memory too.
+    pub fn replace<F>(&self, contents: T, write: F) -> io::Result<()>
+        where F: FnOnce(&mut File, &T) -> io::Result<()> {
+        let mut current = self.contents.write().unwrap_or_else(PoisonError::into_inner);
+        write_atomically(&self.path, |file| write(file, &contents))?;
+        *current = (modified(&self.path), contents);
+        Ok(())
+    }
+}
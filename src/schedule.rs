@@ -0,0 +1,204 @@
+use snapshots::civil_from_days;
+use supervisor::Supervisor;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const POLL_INTERVAL_MILLIS : u64 = 200;
+// Far enough for any valid expression to match, e.g. on the 29th of February
+const MAX_DAYS_AHEAD : i64 = 8 * 366;
+
+/// Values of a cron field as a bit set. Fields starting with `*` aren't restricted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Field {
+    values: u64,
+    restricted: bool,
+}
+
+impl Field {
+    fn contains(&self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+/// Parses a comma separated list of `*`, values & ranges, each with an optional `/step`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Field, String> {
+    let parse_value = |value: &str| value.parse::<u32>().map_err(|_| format!("Invalid value {:?}", value));
+    let mut values = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step in {:?}", part)),
+            },
+            None => (part, 1),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (parse_value(from)?, parse_value(to)?),
+            // `5/15` starts at 5 and goes on to the maximum
+            None if step > 1 => (parse_value(range)?, max),
+            None => (parse_value(range)?, parse_value(range)?),
+        };
+        if from < min || to > max || from > to {
+            return Err(format!("{:?} is out of the range {}-{}", part, min, max));
+        }
+        values = (from..=to).step_by(step as usize).fold(values, |values, value| values | 1 << value);
+    }
+    Ok(Field { values, restricted: !field.starts_with('*') })
+}
+
+/// Cron expression in UTC: minute, hour, day of month, month & day of week (0 or 7 is Sunday)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<CronSchedule, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields in {:?}", expression));
+        }
+        let error = |e: String| format!("Invalid cron expression {:?}: {}", expression, e);
+        let mut weekdays = parse_field(fields[4], 0, 7).map_err(error)?;
+        if weekdays.contains(7) {
+            weekdays.values |= 1;
+        }
+        Ok(CronSchedule {
+            minutes: parse_field(fields[0], 0, 59).map_err(error)?,
+            hours: parse_field(fields[1], 0, 23).map_err(error)?,
+            days: parse_field(fields[2], 1, 31).map_err(error)?,
+            months: parse_field(fields[3], 1, 12).map_err(error)?,
+            weekdays,
+        })
+    }
+
+    /// Like cron, a day matches either restricted day field when both are restricted
+    fn matches_day(&self, days_since_epoch: i64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch % 7 + 11) as u32 % 7;
+        let (day_matches, weekday_matches) = (self.days.contains(day), self.weekdays.contains(weekday));
+        self.months.contains(month) && if self.days.restricted && self.weekdays.restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+
+    /// First minute strictly after `after` which matches the expression
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let minutes = after.duration_since(UNIX_EPOCH).ok()?.as_secs() / 60 + 1;
+        let first_day = (minutes / 1440) as i64;
+        (first_day..first_day + MAX_DAYS_AHEAD)
+            .filter(|day| self.matches_day(*day))
+            .filter_map(|day| {
+                let first_minute = if day == first_day { (minutes % 1440) as u32 } else { 0 };
+                (first_minute..1440)
+                    .find(|minute| self.hours.contains(minute / 60) && self.minutes.contains(minute % 60))
+                    .map(|minute| UNIX_EPOCH + Duration::from_secs(day as u64 * 86_400 + u64::from(minute) * 60))
+            })
+            .next()
+    }
+}
+
+pub fn parse_schedule(expressions: &[String]) -> Result<Vec<CronSchedule>, String> {
+    expressions.iter().map(|expression| CronSchedule::parse(expression)).collect()
+}
+
+/// Earliest time after `after` matching any expression of a schedule
+pub fn next_time(schedule: &[CronSchedule], after: SystemTime) -> Option<SystemTime> {
+    schedule.iter().filter_map(|cron| cron.next_after(after)).min()
+}
+
+/// Requested syncs of a registry. Requests made while a sync is pending or running are coalesced into one sync.
+pub struct SyncTrigger {
+    requested: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl SyncTrigger {
+    pub fn new() -> SyncTrigger {
+        SyncTrigger { requested: Mutex::new(false), wakeup: Condvar::new() }
+    }
+
+    /// Requests a sync, returns false when one was requested already
+    pub fn request(&self) -> bool {
+        let mut requested = self.requested.lock().expect("Sync trigger lock poisoned");
+        let coalesced = *requested;
+        *requested = true;
+        self.wakeup.notify_all();
+        !coalesced
+    }
+
+    /// Waits for a request or the timeout, unless shutting down. Returns whether the task should go on.
+    pub fn wait(&self, timeout: Duration, supervisor: &Supervisor) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut requested = self.requested.lock().expect("Sync trigger lock poisoned");
+        loop {
+            if supervisor.is_shutting_down() {
+                return false;
+            }
+            if *requested {
+                *requested = false;
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            let poll = (deadline - now).min(Duration::from_millis(POLL_INTERVAL_MILLIS));
+            requested = self.wakeup.wait_timeout(requested, poll).expect("Sync trigger lock poisoned").0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // 2026-10-16T17:42:30Z, a Friday
+    fn friday_evening() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_792_172_550)
+    }
+
+    fn at(secs_after_friday_midnight: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_792_108_800 + secs_after_friday_midnight)
+    }
+
+    #[test]
+    fn find_next_scheduled_times() {
+        let every_ten_minutes = CronSchedule::parse("*/10 8-18 * * 1-5").unwrap();
+        assert_eq!(Some(at(17 * 3600 + 50 * 60)), every_ten_minutes.next_after(friday_evening()));
+        // Saturday & Sunday are skipped, to Monday 08:00
+        assert_eq!(Some(at(3 * 86_400 + 8 * 3600)), every_ten_minutes.next_after(at(18 * 3600 + 50 * 60)));
+        let hourly_at_night = CronSchedule::parse("0 19-23,0-7 * * *").unwrap();
+        assert_eq!(Some(at(19 * 3600)), hourly_at_night.next_after(friday_evening()));
+        let schedule = parse_schedule(&[String::from("*/10 8-18 * * 1-5"), String::from("0 19-23,0-7 * * *")]).unwrap();
+        assert_eq!(Some(at(17 * 3600 + 50 * 60)), next_time(&schedule, friday_evening()));
+        // Either the 1st of the month or a Sunday, with 7 as Sunday
+        let first_or_sunday = CronSchedule::parse("30 2 1 * 7").unwrap();
+        assert_eq!(Some(at(2 * 86_400 + 2 * 3600 + 1800)), first_or_sunday.next_after(friday_evening()));
+        assert_eq!(None, CronSchedule::parse("0 0 30 2 *").unwrap().next_after(friday_evening()));
+        assert!(CronSchedule::parse("0 24 * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert!(CronSchedule::parse("0 * * *").is_err());
+    }
+
+    #[test]
+    fn coalesce_sync_requests() {
+        let supervisor = Supervisor::new();
+        let trigger = SyncTrigger::new();
+        assert!(trigger.request());
+        assert!(!trigger.request());
+        let started = Instant::now();
+        assert!(trigger.wait(Duration::from_secs(60), &supervisor));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(trigger.request());
+    }
+}
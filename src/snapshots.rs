@@ -0,0 +1,209 @@
+use git2::{Repository, Error, ErrorCode, ObjectType, Oid};
+use super::config;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SNAPSHOT_TAG_PREFIX : &str = "snapshot-";
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Proleptic Gregorian (year, month, day) of a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Identifies a snapshot by its UTC time, e.g. "2026-10-17T030005".
+/// The seconds tell apart the syncs within the same minute, e.g. when triggered by a hook.
+fn snapshot_id(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let second_of_day = secs % 86_400;
+    format!("{:04}-{:02}-{:02}T{:02}{:02}{:02}", year, month, day, second_of_day / 3600, second_of_day % 3600 / 60, second_of_day % 60)
+}
+
+/// Time of a snapshot, only well-formed snapshot ids are accepted.
+/// Snapshots taken before the ids had seconds are identified by their minute, e.g. "2026-10-17T0300".
+fn parse_snapshot_id(id: &str) -> Option<SystemTime> {
+    let bytes = id.as_bytes();
+    let well_formed = (bytes.len() == 15 || bytes.len() == 17) && bytes.iter().enumerate().all(|(i, byte)| match i {
+        4 | 7 => *byte == b'-',
+        10 => *byte == b'T',
+        _ => byte.is_ascii_digit(),
+    });
+    if !well_formed {
+        return None;
+    }
+    let field = |from: usize, to: usize| id[from..to].parse::<u32>().ok();
+    let (year, month, day) = (field(0, 4)?, field(5, 7)?, field(8, 10)?);
+    let (hours, minutes) = (field(11, 13)?, field(13, 15)?);
+    let seconds = if bytes.len() == 17 { field(15, 17)? } else { 0 };
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+    let days = days_from_civil(i64::from(year), month, day) as u64;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + u64::from(hours * 3600 + minutes * 60 + seconds)))
+}
+
+fn snapshot_tag(id: &str) -> String {
+    format!("{}{}", SNAPSHOT_TAG_PREFIX, id)
+}
+
+/// Ids & times of the snapshots of an index, oldest first
+pub fn list_snapshots(repo: &Repository) -> Result<Vec<(String, SystemTime)>, Error> {
+    let tags = repo.tag_names(Some(&format!("{}*", SNAPSHOT_TAG_PREFIX)))?;
+    let mut snapshots = tags.iter()
+        .flatten()
+        .filter_map(|tag| {
+            let id = tag.trim_start_matches(SNAPSHOT_TAG_PREFIX);
+            parse_snapshot_id(id).map(|time| (String::from(id), time))
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort_by_key(|(_, time)| *time);
+    Ok(snapshots)
+}
+
+fn snapshot_commit_id(repo: &Repository, id: &str) -> Result<Oid, Error> {
+    repo.refname_to_id(&format!("refs/tags/{}", snapshot_tag(id)))
+}
+
+/// Tags the current HEAD as a snapshot, unless the latest snapshot already points to it.
+/// The snapshots outside of the retention policy are removed afterwards.
+pub fn take_snapshot(repo: &Repository, retention: &config::Snapshots, now: SystemTime) -> Result<(), Error> {
+    let head = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
+    let latest_snapshot = list_snapshots(repo)?.pop()
+        .map(|(id, _)| snapshot_commit_id(repo, &id))
+        .map_or(Ok(None), |commit_id| commit_id.map(Some))?;
+    if latest_snapshot != Some(head.id()) {
+        let id = snapshot_id(now);
+        match repo.tag_lightweight(&snapshot_tag(&id), &head, false) {
+            Ok(_) => println!("Created index snapshot {}", id),
+            Err(ref e) if e.code() == ErrorCode::Exists => println!("Index snapshot {} already exists", id),
+            Err(e) => return Err(e),
+        }
+    }
+    prune_snapshots(repo, retention, now)
+}
+
+/// Removes the snapshots older than `keep_days`, and the oldest ones beyond `keep_count`
+fn prune_snapshots(repo: &Repository, retention: &config::Snapshots, now: SystemTime) -> Result<(), Error> {
+    let snapshots = list_snapshots(repo)?;
+    let beyond_count = retention.keep_count
+        .map(|keep_count| snapshots.len().saturating_sub(keep_count))
+        .unwrap_or(0);
+    let oldest_kept = retention.keep_days
+        .map(|keep_days| now - Duration::from_secs(u64::from(keep_days) * 86_400));
+    snapshots.iter()
+        .enumerate()
+        .filter(|(i, (_, time))| *i < beyond_count || oldest_kept.map(|oldest| *time < oldest).unwrap_or(false))
+        .try_for_each(|(_, (id, _))| {
+            println!("Removing index snapshot {}", id);
+            repo.tag_delete(&snapshot_tag(id))
+        })
+}
+
+/// Contents & object id of a file in a snapshot of the index, `None` if either doesn't exist
+pub fn read_snapshot_file(registry_uri: &str, id: &str, file: &str) -> Result<Option<(Oid, Vec<u8>)>, Error> {
+    if parse_snapshot_id(id).is_none() {
+        return Ok(None);
+    }
+    let repo = Repository::open(registry_uri)?;
+    let commit_id = match snapshot_commit_id(&repo, id) {
+        Ok(commit_id) => commit_id,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let tree = repo.find_commit(commit_id)?.tree()?;
+    let entry = match tree.get_path(Path::new(file)) {
+        Ok(entry) => entry,
+        Err(ref e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let object = entry.to_object(&repo)?;
+    Ok(object.as_blob().map(|blob| (blob.id(), blob.content().to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use git2::Signature;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn format_snapshot_ids() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_206_000);
+        assert_eq!("2026-10-17T030000", snapshot_id(time));
+        assert_eq!(Some(time), parse_snapshot_id("2026-10-17T0300"));
+        assert_eq!(Some(time + Duration::from_secs(5)), parse_snapshot_id("2026-10-17T030005"));
+        assert_eq!("2000-02-29T235959", snapshot_id(parse_snapshot_id("2000-02-29T235959").unwrap()));
+        assert_eq!("1970-01-01T000000", snapshot_id(UNIX_EPOCH));
+        assert_eq!(None, parse_snapshot_id("2026-10-17T03:00"));
+        assert_eq!(None, parse_snapshot_id("2026-10-17T030060"));
+        assert_eq!(None, parse_snapshot_id("2026-13-17T0300"));
+        assert_eq!(None, parse_snapshot_id("../../HEAD"));
+    }
+
+    #[test]
+    fn prune_old_snapshots() {
+        let dir = env::temp_dir().join(format!("cargo-mirage-snapshots-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let signature = Signature::now("test", "test@mirage.io").unwrap();
+        let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+        let commit = |message| {
+            let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+            let parents = parent.iter().collect::<Vec<_>>();
+            repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents).unwrap()
+        };
+        let retention = config::Snapshots{ keep_days: Some(30), keep_count: Some(2) };
+        let day = Duration::from_secs(86_400);
+        let start = parse_snapshot_id("2026-01-01T0000").unwrap();
+
+        commit("first");
+        take_snapshot(&repo, &retention, start).unwrap();
+        take_snapshot(&repo, &retention, start + day).unwrap();
+        assert_eq!(1, list_snapshots(&repo).unwrap().len());
+
+        // A second sync within the same minute gets a snapshot of its own
+        commit("second");
+        take_snapshot(&repo, &retention, start + Duration::from_secs(20)).unwrap();
+        let ids = list_snapshots(&repo).unwrap().into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(vec![String::from("2026-01-01T000000"), String::from("2026-01-01T000020")], ids);
+
+        commit("third");
+        take_snapshot(&repo, &retention, start + day).unwrap();
+        commit("fourth");
+        take_snapshot(&repo, &retention, start + day * 2).unwrap();
+        let ids = list_snapshots(&repo).unwrap().into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(vec![String::from("2026-01-02T000000"), String::from("2026-01-03T000000")], ids);
+
+        commit("fifth");
+        take_snapshot(&repo, &retention, start + day * 40).unwrap();
+        let ids = list_snapshots(&repo).unwrap().into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(vec![String::from("2026-02-10T000000")], ids);
+
+        let head = repo.head().unwrap().target().unwrap();
+        assert_eq!(head, snapshot_commit_id(&repo, "2026-02-10T000000").unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MIN_BACKOFF_SECS : u64 = 5;
+const MAX_BACKOFF_SECS : u64 = 300;
+const POLL_INTERVAL_MILLIS : u64 = 200;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Degraded, // Running, but the upstream is unavailable
+    Failed, // Waiting to be restarted
+    Stopped,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u32,
+    pub failures: u32, // Upstream failures since the last success
+    pub last_error: Option<String>,
+    pub last_success: Option<u64>, // Unix time of the last upstream success
+    pub since: u64, // Unix time of the last state change
+}
+
+/// Runs the background tasks, restarting the ones which fail with an exponential backoff,
+/// and keeps track of the work which has to be finished before shutting down
+pub struct Supervisor {
+    shutting_down: AtomicBool,
+    in_flight: AtomicUsize,
+    tasks: Mutex<BTreeMap<String, TaskStatus>>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+/// Work in progress, e.g. a crate download, which is waited for on shutdown until dropped
+pub struct InFlight(Arc<Supervisor>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<String>().cloned()
+        .or_else(|| payload.downcast_ref::<&str>().map(|message| String::from(*message)))
+        .unwrap_or_else(|| String::from("Unknown panic"))
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor::with_backoff(Duration::from_secs(MIN_BACKOFF_SECS), Duration::from_secs(MAX_BACKOFF_SECS))
+    }
+
+    fn with_backoff(min_backoff: Duration, max_backoff: Duration) -> Supervisor {
+        Supervisor {
+            shutting_down: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            tasks: Mutex::new(BTreeMap::new()),
+            min_backoff,
+            max_backoff,
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps unless shutting down. Returns whether the task should go on.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.is_shutting_down() {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(POLL_INTERVAL_MILLIS)));
+        }
+        false
+    }
+
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub fn tasks(&self) -> Vec<TaskStatus> {
+        self.tasks.lock().map(|tasks| tasks.values().cloned().collect()).unwrap_or_default()
+    }
+
+    fn set_state(&self, name: &str, state: TaskState, error: Option<String>) {
+        if let Ok(mut tasks) = self.tasks.lock() {
+            let status = tasks.entry(String::from(name)).or_insert_with(|| TaskStatus {
+                name: String::from(name),
+                state,
+                restarts: 0,
+                failures: 0,
+                last_error: None,
+                last_success: None,
+                since: 0,
+            });
+            if status.state == TaskState::Failed && state == TaskState::Running {
+                status.restarts += 1;
+            }
+            status.state = state;
+            status.since = unix_secs(SystemTime::now());
+            if error.is_some() {
+                status.last_error = error;
+            }
+        }
+    }
+
+    /// Records that a running task reached upstream
+    pub fn report_success(&self, name: &str) {
+        self.set_state(name, TaskState::Running, None);
+        if let Ok(mut tasks) = self.tasks.lock() {
+            if let Some(status) = tasks.get_mut(name) {
+                status.failures = 0;
+                status.last_success = Some(status.since);
+            }
+        }
+    }
+
+    /// Records that a running task couldn't reach upstream, returning the number of failures in a row
+    pub fn report_failure(&self, name: &str, error: String) -> u32 {
+        self.set_state(name, TaskState::Degraded, Some(error));
+        self.tasks.lock().ok()
+            .and_then(|mut tasks| tasks.get_mut(name).map(|status| {
+                status.failures += 1;
+                status.failures
+            }))
+            .unwrap_or(1)
+    }
+
+    /// Records the error of a running task which still reached upstream, e.g. data it had to skip
+    pub fn report_error(&self, name: &str, error: String) {
+        self.set_state(name, TaskState::Running, Some(error));
+    }
+
+    /// Exponential backoff after a number of failures in a row
+    pub fn backoff(&self, failures: u32) -> Duration {
+        (1..failures).fold(self.min_backoff, |backoff, _| (backoff * 2).min(self.max_backoff))
+    }
+
+    /// Whether a degraded task should wait for its backoff before reaching upstream again
+    pub fn is_backing_off(&self, name: &str) -> bool {
+        let now = unix_secs(SystemTime::now());
+        self.tasks.lock().ok()
+            .and_then(|tasks| tasks.get(name).map(|status| status.state == TaskState::Degraded
+                && now < status.since + self.backoff(status.failures).as_secs()))
+            .unwrap_or(false)
+    }
+
+    /// Runs a task on its own thread until it returns, which it does once shutting down.
+    /// A task which panics is restarted after a backoff, which is reset once it ran for longer than the maximum backoff.
+    pub fn supervise<F>(self: &Arc<Self>, name: &str, task: F) where F: Fn() + Send + 'static {
+        let supervisor = self.clone();
+        let name = String::from(name);
+        thread::spawn(move || {
+            let mut failures = 0;
+            loop {
+                supervisor.set_state(&name, TaskState::Running, None);
+                let started = Instant::now();
+                let error = match panic::catch_unwind(AssertUnwindSafe(&task)) {
+                    Ok(()) => break,
+                    Err(payload) => panic_message(payload.as_ref()),
+                };
+                failures = if started.elapsed() > supervisor.max_backoff { 1 } else { failures + 1 };
+                let backoff = supervisor.backoff(failures);
+                eprintln!("Task {} failed, restarting in {}s: {}", name, backoff.as_secs(), error);
+                supervisor.set_state(&name, TaskState::Failed, Some(error));
+                if !supervisor.sleep(backoff) {
+                    break;
+                }
+            }
+            supervisor.set_state(&name, TaskState::Stopped, None);
+        });
+    }
+
+    /// Stops the tasks, then waits for them & the work in flight to finish, at most for `timeout`
+    pub fn shutdown(&self, timeout: Duration) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let finished = || self.in_flight.load(Ordering::SeqCst) == 0
+            && self.tasks().iter().all(|task| task.state == TaskState::Stopped);
+        while !finished() {
+            if Instant::now() >= deadline {
+                eprintln!("Stopping with {} downloads in flight, left to the next crawl", self.in_flight.load(Ordering::SeqCst));
+                return;
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn restart_failed_tasks_until_shutdown() {
+        let supervisor = Arc::new(Supervisor::with_backoff(Duration::from_millis(10), Duration::from_millis(50)));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let task_runs = runs.clone();
+        let task_supervisor = supervisor.clone();
+        supervisor.supervise("flaky", move || {
+            let in_flight = task_supervisor.track();
+            if task_runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("Upstream is down");
+            }
+            while task_supervisor.sleep(Duration::from_millis(10)) {}
+            drop(in_flight);
+        });
+        while runs.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        supervisor.shutdown(Duration::from_secs(5));
+        let tasks = supervisor.tasks();
+        assert_eq!(1, tasks.len());
+        assert_eq!(TaskState::Stopped, tasks[0].state);
+        assert_eq!(2, tasks[0].restarts);
+        assert_eq!(Some(String::from("Upstream is down")), tasks[0].last_error);
+        assert_eq!(0, supervisor.in_flight.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn report_upstream_failures() {
+        let supervisor = Supervisor::with_backoff(Duration::from_secs(5), Duration::from_secs(60));
+        supervisor.set_state("registry", TaskState::Running, None);
+        assert_eq!(1, supervisor.report_failure("registry", String::from("Connection refused")));
+        assert_eq!(2, supervisor.report_failure("registry", String::from("Connection refused")));
+        assert_eq!(TaskState::Degraded, supervisor.tasks()[0].state);
+        assert!(supervisor.is_backing_off("registry"));
+        assert!(!supervisor.is_backing_off("crawler"));
+        assert_eq!(Duration::from_secs(10), supervisor.backoff(2));
+        assert_eq!(Duration::from_secs(60), supervisor.backoff(10));
+        supervisor.report_success("registry");
+        assert!(!supervisor.is_backing_off("registry"));
+        let status = &supervisor.tasks()[0];
+        assert_eq!((TaskState::Running, 0, 0), (status.state, status.failures, status.restarts));
+        assert!(status.last_success.is_some());
+        supervisor.report_error("registry", String::from("Invalid entry"));
+        assert_eq!((TaskState::Running, Some(String::from("Invalid entry"))), (supervisor.tasks()[0].state, supervisor.tasks()[0].last_error.clone()));
+    }
+}
@@ -0,0 +1,173 @@
+use super::config;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TRANSFER_CHUNK_SIZE : usize = 16 * 1024;
+
+/// Parses a "HH:MM" time of day into minutes since midnight
+fn parse_time_of_day(time: &str) -> Option<u32> {
+    let mut parts = time.trim().splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<u32>().ok())?;
+    let minutes = parts.next().and_then(|minutes| minutes.parse::<u32>().ok())?;
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+/// Time window in minutes since midnight, wrapping around midnight when `from` is past `to`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct TimeWindow {
+    from: u32,
+    to: u32,
+}
+
+impl TimeWindow {
+    fn parse(window: &config::TimeWindow) -> Option<TimeWindow> {
+        match (parse_time_of_day(&window.from), parse_time_of_day(&window.to)) {
+            (Some(from), Some(to)) => Some(TimeWindow { from, to }),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, minute_of_day: u32) -> bool {
+        if self.from <= self.to {
+            self.from <= minute_of_day && minute_of_day < self.to
+        } else {
+            self.from <= minute_of_day || minute_of_day < self.to
+        }
+    }
+}
+
+fn current_minute_of_day() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since_epoch| (since_epoch.as_secs() % 86_400 / 60) as u32)
+        .unwrap_or(0)
+}
+
+fn duration_from_secs_f64(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+/// Reserves `cost` on a shared schedule, returns when the reservation starts
+fn reserve(schedule: &Mutex<Instant>, cost: Duration, now: Instant) -> Instant {
+    let mut next_free = schedule.lock().expect("Throttle lock poisoned");
+    let start = cmp::max(*next_free, now);
+    *next_free = start + cost;
+    start
+}
+
+fn wait_until(instant: Instant) {
+    let now = Instant::now();
+    if instant > now {
+        thread::sleep(instant - now);
+    }
+}
+
+/// Limits the aggregate request rate & bandwidth of all crawlers towards the upstream registries
+pub struct Throttle {
+    max_bandwidth: Option<u64>,
+    max_requests: Option<f64>,
+    full_speed: Vec<TimeWindow>,
+    next_request: Mutex<Instant>,
+    next_transfer: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(crawling: &config::Crawling) -> Throttle {
+        let full_speed = crawling.full_speed.iter()
+            .filter_map(|window| {
+                let parsed = TimeWindow::parse(window);
+                if parsed.is_none() {
+                    eprintln!("Ignoring invalid full speed window {:?}", window);
+                }
+                parsed
+            })
+            .collect();
+        Throttle {
+            max_bandwidth: crawling.max_bandwidth.filter(|bandwidth| *bandwidth > 0),
+            max_requests: crawling.max_requests.filter(|requests| *requests > 0.0),
+            full_speed,
+            next_request: Mutex::new(Instant::now()),
+            next_transfer: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn is_full_speed(&self) -> bool {
+        let minute_of_day = current_minute_of_day();
+        self.full_speed.iter().any(|window| window.contains(minute_of_day))
+    }
+
+    /// Blocks until a new upstream request is allowed
+    pub fn wait_for_request(&self) {
+        if let Some(max_requests) = self.max_requests {
+            if !self.is_full_speed() {
+                wait_until(reserve(&self.next_request, duration_from_secs_f64(1.0 / max_requests), Instant::now()));
+            }
+        }
+    }
+
+    fn wait_for_transfer(&self, bytes: usize) {
+        if let Some(max_bandwidth) = self.max_bandwidth {
+            if !self.is_full_speed() {
+                let cost = duration_from_secs_f64(bytes as f64 / max_bandwidth as f64);
+                wait_until(reserve(&self.next_transfer, cost, Instant::now()));
+            }
+        }
+    }
+
+    /// Copies a download within the bandwidth limit
+    pub fn copy<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> io::Result<u64> {
+        let mut buffer = [0u8; TRANSFER_CHUNK_SIZE];
+        let mut copied = 0u64;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(copied),
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.wait_for_transfer(read);
+            writer.write_all(&buffer[..read])?;
+            copied += read as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn window(from: &str, to: &str) -> Option<TimeWindow> {
+        TimeWindow::parse(&config::TimeWindow{ from: String::from(from), to: String::from(to) })
+    }
+
+    #[test]
+    fn match_time_windows() {
+        let office_hours = window("08:30", "18:00").unwrap();
+        assert!(office_hours.contains(8 * 60 + 30));
+        assert!(!office_hours.contains(18 * 60));
+        let night = window("22:00", "06:00").unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(60));
+        assert!(!night.contains(12 * 60));
+        assert_eq!(None, window("24:00", "06:00"));
+        assert_eq!(None, window("noon", "06:00"));
+    }
+
+    #[test]
+    fn reserve_consecutive_slots() {
+        let now = Instant::now();
+        let schedule = Mutex::new(now);
+        let cost = Duration::from_millis(500);
+        assert_eq!(now, reserve(&schedule, cost, now));
+        assert_eq!(now + cost, reserve(&schedule, cost, now));
+        let later = now + Duration::from_secs(5);
+        assert_eq!(later, reserve(&schedule, cost, later));
+    }
+}